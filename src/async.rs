@@ -0,0 +1,526 @@
+use std::io;
+use serde::de::DeserializeOwned;
+use crate::async_stream_wrapper::AsyncStreamWrapper;
+use crate::channel::{Channel, ChannelKind};
+use crate::channel_allocator::ChannelAllocator;
+use crate::command::{AddressBooks, Command, CommandKind, PushToken};
+use crate::command_response::{
+    ActivateUserResponse,
+    Actuator,
+    AuthResponse,
+    ChannelOpened,
+    ConfigurationResponse,
+    FaceRecognitionParams,
+    FaceRecognitionParamsResponse,
+    InfoResponse,
+    PushInfoResponse,
+    RemoveUserResponse,
+    ResponseHeader,
+    SetFaceRecognitionParamsResponse,
+    UserListResponse,
+    VipResponse
+};
+use crate::ctpp_channel::CTPPChannel;
+use crate::door::{DoorLookup, DoorOpening, DOOR_MODULE};
+use crate::frame::FrameError;
+use crate::{JSONResult, ViperClientBuilder, ViperError};
+
+/// The non-blocking counterpart of [`crate::ViperClient`], built on top
+/// of tokio. Every operation mirrors the blocking client, but awaits the
+/// socket instead of blocking the current thread.
+pub struct ViperClient {
+    stream: AsyncStreamWrapper,
+    channels: ChannelAllocator,
+    message_id: u32,
+    info: Option<InfoResponse>,
+    configuration: Option<ConfigurationResponse>
+}
+
+impl ViperClient {
+    /// Connects with the default timeouts, use [`ViperClientBuilder`]
+    /// to configure them.
+    pub async fn connect(ip: &str, port: &str) -> Result<ViperClient, ViperError> {
+        ViperClientBuilder::new(ip, port).connect_async().await
+    }
+
+    pub(crate) fn from_stream(stream: AsyncStreamWrapper) -> ViperClient {
+        ViperClient {
+            stream,
+            channels: ChannelAllocator::new(),
+            message_id: 1,
+            info: None,
+            configuration: None
+        }
+    }
+
+    pub async fn sign_up(&mut self, email: &String) -> JSONResult<ActivateUserResponse> {
+        let activate_user = CommandKind::ActivateUser(String::from(email));
+        self.request(ChannelKind::FACT, activate_user).await
+    }
+
+    pub async fn remove_all_users(&mut self, email: &String) -> JSONResult<serde_json::Value> {
+        let remove_all_users = CommandKind::RemoveAllUsers(String::from(email));
+        self.request(ChannelKind::FACT, remove_all_users).await
+    }
+
+    pub async fn users(&mut self) -> JSONResult<UserListResponse> {
+        self.request(ChannelKind::UADM, CommandKind::ListUsers).await
+    }
+
+    pub async fn remove_user(&mut self, user_token: &str) -> JSONResult<RemoveUserResponse> {
+        let remove_user = CommandKind::RemoveUser(user_token.to_string());
+        self.request(ChannelKind::UADM, remove_user).await
+    }
+
+    pub async fn authorize(&mut self, token: String) -> JSONResult<AuthResponse> {
+        self.request(ChannelKind::UAUT, CommandKind::UAUT(token)).await
+    }
+
+    pub async fn configuration(&mut self, addressbooks: AddressBooks) -> JSONResult<ConfigurationResponse> {
+        self.request(ChannelKind::UCFG, CommandKind::UCFG(addressbooks)).await
+    }
+
+    pub async fn register_push_token(&mut self, token: &PushToken) -> JSONResult<PushInfoResponse> {
+        self.request(ChannelKind::PUSH, CommandKind::PushInfo(token.clone())).await
+    }
+
+    /// Registers `token` again with an empty device token, which is
+    /// assumed but not confirmed to stop the push notifications.
+    pub async fn unregister_push_token(&mut self, token: &PushToken) -> JSONResult<PushInfoResponse> {
+        let token = PushToken {
+            device_token: String::new(),
+            ..token.clone()
+        };
+
+        self.register_push_token(&token).await
+    }
+
+    pub async fn info(&mut self) -> JSONResult<InfoResponse> {
+        let info: InfoResponse = self.send(
+            ChannelKind::INFO,
+            CommandKind::INFO.message(),
+            |channel, message_id| channel.com(CommandKind::INFO, message_id)
+        ).await?;
+        self.info = Some(info.clone());
+        Ok(info)
+    }
+
+    pub async fn face_recognition_params(&mut self) -> JSONResult<FaceRecognitionParamsResponse> {
+        self.request(ChannelKind::FRCG, CommandKind::FRCG).await
+    }
+
+    pub async fn set_face_recognition_params(&mut self,
+                                             params: &FaceRecognitionParams) -> JSONResult<SetFaceRecognitionParamsResponse> {
+
+        let frcg = CommandKind::SetFaceRecognitionParams(params.clone());
+        self.request(ChannelKind::FRCG, frcg).await
+    }
+
+    pub async fn open_door(&mut self, vip: &VipResponse) -> Result<(), ViperError> {
+        let door = DoorOpening::first_door(vip)?;
+        self.link_actuator(vip, &door.actuator_id(), DOOR_MODULE).await
+    }
+
+    pub async fn open_door_by_name(&mut self, name: &str) -> Result<(), ViperError> {
+        self.open_door_by(DoorLookup::Name(name)).await
+    }
+
+    pub async fn open_door_by_id(&mut self, id: &str) -> Result<(), ViperError> {
+        self.open_door_by(DoorLookup::Id(id)).await
+    }
+
+    pub async fn activate_actuator(&mut self, actuator: &Actuator) -> Result<(), ViperError> {
+        let config = self.take_configuration().await?;
+        let result = self.link_actuator(
+            &config.vip,
+            &actuator.actuator_id(),
+            actuator.module_index
+        ).await;
+
+        self.configuration = Some(config);
+        result
+    }
+
+    async fn open_door_by(&mut self, lookup: DoorLookup<'_>) -> Result<(), ViperError> {
+        let config = self.take_configuration().await?;
+        let result = match lookup.actuator_id(&config.vip.user_parameters) {
+            Ok(actuator_id) => self.link_actuator(&config.vip, &actuator_id, DOOR_MODULE).await,
+            Err(e) => Err(ViperError::from(e))
+        };
+
+        self.configuration = Some(config);
+        result
+    }
+
+    // Has to be put back by the caller once it's done with it
+    async fn take_configuration(&mut self) -> JSONResult<ConfigurationResponse> {
+        match self.configuration.take() {
+            Some(config) => Ok(config),
+            None => self.configuration(AddressBooks::All).await
+        }
+    }
+
+    async fn link_actuator(&mut self,
+                           vip: &VipResponse,
+                           actuator_id: &str,
+                           module_index: u8) -> Result<(), ViperError> {
+
+        let ctpp_channel = self.ctpp_channel()?;
+        let control = ctpp_channel.control;
+
+        let mut door = DoorOpening::new(ctpp_channel, vip, actuator_id, module_index);
+        let result = self.door_sequence(&mut door, &control).await;
+
+        self.finish(&control, &result).await?;
+        result
+    }
+
+    async fn door_sequence(&mut self,
+                           door: &mut DoorOpening,
+                           control: &[u8; 2]) -> Result<(), ViperError> {
+
+        let reply = self.stream.execute(&door.open()).await?;
+        if ChannelOpened::parse(&reply).is_none() {
+            return Err(ViperError::ChannelRefused(ChannelKind::CTPP, reply))
+        }
+
+        self.stream.write(&door.handshake()).await?;
+
+        while !door.is_opened() {
+            let reply = match self.stream.read(control).await {
+                Ok(reply) => reply,
+                Err(e) if e.kind() == io::ErrorKind::TimedOut =>
+                    return Err(ViperError::from(door.timed_out())),
+                Err(e) => return Err(ViperError::from(e))
+            };
+
+            for request in door.receive(&reply)? {
+                self.stream.write(&request).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // The socket is shut down even when one of the channels fails to
+    // close, the first error is returned afterwards.
+    pub async fn shutdown(&mut self) -> Result<(), ViperError> {
+        let mut result = Ok(());
+
+        for control in self.channels.open_channels() {
+            if let Err(e) = self.close(&control).await {
+                result = Err(e);
+                break;
+            }
+        }
+
+        self.stream.die().await?;
+        result
+    }
+
+    pub async fn request_json(&mut self,
+                              channel: ChannelKind,
+                              request: serde_json::Value) -> JSONResult<serde_json::Value> {
+
+        let message = Command::message_of(&request)?;
+
+        self.require(channel).await?;
+        self.send(
+            channel,
+            &message,
+            |channel, message_id| Command::json(request, message_id, &channel.control)
+        ).await
+    }
+
+    async fn request<T>(&mut self,
+                        channel: ChannelKind,
+                        kind: CommandKind) -> JSONResult<T>
+        where T: DeserializeOwned {
+
+        self.require(channel).await?;
+        self.send(
+            channel,
+            kind.message(),
+            |channel, message_id| channel.com(kind, message_id)
+        ).await
+    }
+
+    // See the blocking client, the INFO response is cached the same way
+    async fn require(&mut self, kind: ChannelKind) -> Result<(), ViperError> {
+        if kind.capability().is_none() {
+            return Ok(())
+        }
+
+        if self.info.is_none() {
+            self.info().await?;
+        }
+
+        match &self.info {
+            Some(info) => info.require(kind),
+            None => Ok(())
+        }
+    }
+
+    // Opens a channel, executes a single command on it and closes the
+    // channel again before parsing the response. The channel is closed
+    // even when the command itself fails.
+    async fn send<T, F>(&mut self,
+                        channel: ChannelKind,
+                        message: &str,
+                        command: F) -> JSONResult<T>
+        where T: DeserializeOwned,
+              F: FnOnce(&Channel, u32) -> Result<Vec<u8>, FrameError> {
+
+        let channel = self.channel(channel)?;
+        let result = self.exchange(&channel, message, command).await;
+        self.finish(&channel.control, &result).await?;
+
+        crate::ViperClient::json(&result?)
+    }
+
+    async fn exchange<F>(&mut self,
+                         channel: &Channel,
+                         message: &str,
+                         command: F) -> Result<Vec<u8>, ViperError>
+        where F: FnOnce(&Channel, u32) -> Result<Vec<u8>, FrameError> {
+
+        let message_id = self.message_id;
+        self.message_id += 1;
+
+        let reply = self.stream.execute(&channel.open()).await?;
+        if ChannelOpened::parse(&reply).is_none() {
+            return Err(ViperError::ChannelRefused(channel.kind(), reply))
+        }
+
+        let bytes = self.stream.execute(&command(channel, message_id)?).await?;
+
+        ResponseHeader::verify(&bytes, message, message_id)?;
+        Ok(bytes)
+    }
+
+    fn channel(&mut self, kind: ChannelKind) -> Result<Channel, ViperError> {
+        let control = self.channels.allocate()?;
+
+        Ok(Channel::new(&control, kind))
+    }
+
+    fn ctpp_channel(&mut self) -> Result<CTPPChannel, ViperError> {
+        let control = self.channels.allocate()?;
+
+        Ok(CTPPChannel::new(&control))
+    }
+
+    // A channel the device refused was never opened, so there's nothing
+    // to close and only its control bytes are given back.
+    async fn finish<T>(&mut self,
+                       control: &[u8; 2],
+                       result: &Result<T, ViperError>) -> Result<(), ViperError> {
+
+        match result {
+            Err(ViperError::ChannelRefused(..)) => {
+                self.release(control);
+                Ok(())
+            },
+            _ => self.close(control).await
+        }
+    }
+
+    // Closes a channel and drops any frame that's still queued up for it
+    async fn close(&mut self, control: &[u8; 2]) -> Result<(), ViperError> {
+        let result = self.stream.execute(&Command::close(control)).await;
+        self.release(control);
+
+        result?;
+        Ok(())
+    }
+
+    fn release(&mut self, control: &[u8; 2]) {
+        self.stream.discard(control);
+        self.channels.release(control);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::test_helper::{
+        confirm,
+        with_message_id,
+        SimpleTcpListener,
+        CONFIGURATION_RESPONSE,
+        INFO_RESPONSE,
+        OPENED
+    };
+
+    #[tokio::test]
+    async fn test_authorize() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3342");
+
+        thread::spawn(move || {
+            let mocked_json = r#"{
+                "message":"access",
+                "message-type":"response",
+                "message-id":2,
+                "response-code":200,
+                "response-string":"Access Granted"
+            }"#;
+
+            listener.mock_replies(
+                vec![
+                    &OPENED,
+                    INFO_RESPONSE.as_bytes(),
+                    &[],
+                    &OPENED,
+                    mocked_json.as_bytes(),
+                    &[] // Closing the channel
+                ]
+            )
+        });
+
+        let mut client = ViperClient::connect("127.0.0.1", "3342")
+            .await
+            .unwrap();
+
+        let resp = client.authorize(String::from("TESTTOKEN")).await.unwrap();
+        assert_eq!(resp.response.response_string, "Access Granted");
+        assert_eq!(resp.response.response_code, 200)
+    }
+
+    #[tokio::test]
+    async fn test_refused_channel_is_not_closed() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3366");
+
+        // Anything but an open ack refuses the channel
+        let server = thread::spawn(move || {
+            listener.serve(|_| vec![vec![0x00, 0x00, 0x00, 0x00]])
+        });
+
+        let mut client = ViperClient::connect("127.0.0.1", "3366")
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            client.info().await,
+            Err(ViperError::ChannelRefused(ChannelKind::INFO, _))
+        ));
+        client.shutdown().await.unwrap();
+
+        // Only the open request went out
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(&requests[0].payload[..4], &[0xcd, 0xab, 0x01, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn test_info_and_configuration() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3367");
+
+        let server = thread::spawn(move || {
+            let configuration = with_message_id(CONFIGURATION_RESPONSE, 2);
+
+            listener.mock_replies(
+                vec![
+                    &OPENED, INFO_RESPONSE.as_bytes(), &[],
+                    &OPENED, &configuration, &[]
+                ]
+            ).unwrap()
+        });
+
+        let mut client = ViperClient::connect("127.0.0.1", "3367")
+            .await
+            .unwrap();
+
+        let info = client.info().await.unwrap();
+        assert_eq!(info.model, "MSVF");
+
+        // UCFG needs a capability, which is checked against the INFO
+        // response from before instead of asking again.
+        let config = client.configuration(AddressBooks::All).await.unwrap();
+        assert_eq!(config.vip.apt_address, "SB000006");
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 6);
+        assert_eq!(&requests[0].payload[8..12], b"INFO");
+        assert_eq!(&requests[3].payload[8..12], b"UCFG");
+    }
+
+    #[tokio::test]
+    async fn test_sign_up() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3368");
+
+        let server = thread::spawn(move || {
+            let activated = r#"{
+                "message":"activate-user",
+                "message-type":"response",
+                "message-id":2,
+                "response-code":200,
+                "response-string":"OK",
+                "user-token":"AAAA"
+            }"#;
+
+            listener.mock_replies(
+                vec![
+                    &OPENED, INFO_RESPONSE.as_bytes(), &[],
+                    &OPENED, activated.as_bytes(), &[]
+                ]
+            ).unwrap()
+        });
+
+        let mut client = ViperClient::connect("127.0.0.1", "3368")
+            .await
+            .unwrap();
+
+        let resp = client.sign_up(&String::from("test@test.com")).await.unwrap();
+        assert_eq!(resp.user_token, "AAAA");
+
+        let requests = server.join().unwrap();
+        assert_eq!(&requests[3].payload[8..12], b"FACT");
+
+        let request: serde_json::Value = serde_json::from_slice(&requests[4].payload).unwrap();
+        assert_eq!(request["message"], "activate-user");
+        assert_eq!(request["email"], "test@test.com");
+    }
+
+    #[tokio::test]
+    async fn test_open_door() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3369");
+
+        // Confirms the handshake and the link, like the panel does
+        let server = thread::spawn(move || {
+            listener.serve(|request| {
+                let payload = &request.payload;
+
+                match (payload[0], payload[6..8] == [0x00, 0x11]) {
+                    (0xcd, _) => vec![OPENED.to_vec()],
+                    (0xc0, true) => vec![confirm(0x60, payload)],
+                    (0xc0, false) => vec![confirm(0x00, payload)],
+                    (0xef, _) => vec![vec![]],
+                    _ => vec![]
+                }
+            })
+        });
+
+        let mut client = ViperClient::connect("127.0.0.1", "3369")
+            .await
+            .unwrap();
+
+        let config: ConfigurationResponse = serde_json::from_str(CONFIGURATION_RESPONSE).unwrap();
+        client.open_door(&config.vip).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let requests = server.join().unwrap();
+        let link = requests
+            .iter()
+            .map(|request| &request.payload)
+            .find(|payload| payload[0] == 0xc0 && payload[6..8] != [0x00, 0x11])
+            .unwrap();
+
+        assert_eq!(&link[10..19], b"SB1000011");
+        assert_eq!(link[30], DOOR_MODULE);
+
+        // Only the CTPP channel was opened, and it was closed again
+        assert_eq!(requests.iter().filter(|request| request.payload[0] == 0xcd).count(), 1);
+        assert_eq!(requests.last().unwrap().payload[0], 0xef);
+    }
+}
@@ -0,0 +1,132 @@
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use crate::frame::{Frame, HEADER_LENGTH};
+use crate::multiplexer::Queues;
+use crate::stream_wrapper::Timeouts;
+
+type ByteResult = Result<Vec<u8>, io::Error>;
+
+pub struct AsyncStreamWrapper {
+    writer: OwnedWriteHalf,
+    queues: Arc<Queues>,
+    reader: JoinHandle<()>,
+    timeouts: Timeouts
+}
+
+impl AsyncStreamWrapper {
+    pub async fn connect(ip: &str, timeouts: &Timeouts) -> Result<AsyncStreamWrapper, io::Error> {
+        let stream = Self::timed(
+            timeouts.connect,
+            TcpStream::connect(ip)
+        ).await?;
+
+        let (read_half, writer) = stream.into_split();
+        let queues = Arc::new(Queues::default());
+        let reader = tokio::spawn(
+            Self::demultiplex(read_half, Arc::clone(&queues))
+        );
+
+        Ok(AsyncStreamWrapper {
+            writer,
+            queues,
+            reader,
+            timeouts: *timeouts
+        })
+    }
+
+    // Writes the bytes and waits for the reply on the same channel
+    pub async fn execute(&mut self, b: &[u8]) -> ByteResult {
+        self.write(b).await?;
+        self.read(&[b[4], b[5]]).await
+    }
+
+    pub async fn die(&mut self) -> Result<(), io::Error> {
+        self.writer.shutdown().await
+    }
+
+    pub async fn write(&mut self, b: &[u8]) -> Result<(), io::Error> {
+        Self::timed(self.timeouts.write, self.writer.write_all(b)).await
+    }
+
+    pub async fn read(&mut self, channel: &[u8; 2]) -> ByteResult {
+        Ok(self.read_frame(channel).await?.payload)
+    }
+
+    pub async fn read_frame(&mut self, channel: &[u8; 2]) -> Result<Frame, io::Error> {
+        self.queues.recv_async(channel, self.timeouts.read).await
+    }
+
+    // Drops any frame that is still queued up for a closed channel
+    pub fn discard(&self, channel: &[u8; 2]) {
+        self.queues.discard(channel)
+    }
+
+    async fn timed<T, F>(duration: Duration, future: F) -> Result<T, io::Error>
+        where F: Future<Output = Result<T, io::Error>> {
+
+        match timeout(duration, future).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut))
+        }
+    }
+
+    async fn demultiplex(mut stream: OwnedReadHalf, queues: Arc<Queues>) {
+        loop {
+            match Self::next_frame(&mut stream).await {
+                Ok(frame) => queues.push(frame),
+                Err(e) => {
+                    queues.fail(&e);
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn next_frame(stream: &mut OwnedReadHalf) -> Result<Frame, io::Error> {
+        let mut head = [0; HEADER_LENGTH];
+        stream.read_exact(&mut head).await?;
+
+        let (channel, length) = Frame::decode_header(&head)?;
+        let mut payload = vec![0; length];
+        stream.read_exact(&mut payload).await?;
+
+        Ok(Frame { channel, payload })
+    }
+}
+
+impl Drop for AsyncStreamWrapper {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str;
+    use std::thread;
+    use crate::command::Command;
+    use crate::test_helper::SimpleTcpListener;
+
+    #[tokio::test]
+    async fn test_execute() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3336");
+        thread::spawn(move || listener.echo());
+
+        let mut client = AsyncStreamWrapper::connect(
+            "127.0.0.1:3336",
+            &Timeouts::default()
+        ).await.unwrap();
+
+        let command = Command::make(&[65, 65], &[0, 0]).unwrap();
+        let response = client.execute(&command).await.unwrap();
+        assert_eq!(str::from_utf8(&response).unwrap(), "AA");
+    }
+}
@@ -0,0 +1,842 @@
+use std::{fmt, fmt::Display, io};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::mpsc::{self, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::SystemTime;
+use crate::channel::ChannelKind;
+use crate::command_response::VipResponse;
+use crate::connection::Connection;
+use crate::ctpp_channel::CTPPChannel;
+use crate::ctpp_packet::{CtppPacket, CtppPacketKind};
+use crate::ViperError;
+
+// The first two bytes of the body of a 0xc0 packet tell what it's for,
+// like 00 40 for the handshake and 00 01 for linking actuators. None of
+// the call actions below have been captured from a device yet, they're
+// unverified guesses that follow the same layout.
+
+// What an entrance panel is expected to send when it rings the apartment
+pub(crate) const RING_ACTION: [u8; 2] = [0x00, 0x20];
+
+// Hypothesis: sent by the apartment to pick up, taken as the code that
+// follows the ring.
+const ANSWER_ACTION: [u8; 2] = [0x00, 0x21];
+
+// Hypothesis: ends the call, whichever side sends it. The panel would
+// also send it once it gives up ringing, and the apartment sends it to
+// reject a ring as well, since no separate code is known.
+const HANGUP_ACTION: [u8; 2] = [0x00, 0x22];
+
+#[derive(Debug)]
+pub enum Event {
+    // Someone rang at the entrance, which calls the apartment address
+    IncomingCall {
+        entrance: String,
+        apt_address: String,
+        timestamp: SystemTime
+    },
+    // The entrance panel moved the call along, like hanging up
+    CallStateChanged(CallState),
+    // The CTPP channel can't be read from anymore
+    Closed(ViperError)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallState {
+    Ringing,
+    // Answered, until the panel acknowledges it
+    Answering,
+    Connected,
+    // Hung up after it was answered, or rejected
+    Ended,
+    // The panel stopped ringing before it was answered
+    Missed
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CallError {
+    // Nobody rang, or the listener is no longer running
+    NoCall,
+    // The call can't be answered, rejected or hung up in this state
+    InvalidState(CallState)
+}
+
+impl Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallError::NoCall =>
+                write!(f, "There's no incoming call"),
+            CallError::InvalidState(state) =>
+                write!(f, "The call is {:?}", state),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum CallAction {
+    Answer,
+    Reject,
+    HangUp
+}
+
+// A single call, from the ring onwards. Everything the apartment sends
+// goes into the exchange of the panel, see docs/README.md.
+struct Call {
+    exchange: [u8; 2],
+    entrance: String,
+    remote: u8,
+    local: u8,
+    state: CallState
+}
+
+impl Call {
+    fn ringing(packet: &CtppPacket, entrance: String) -> Call {
+        Call {
+            exchange: [packet.sequence[0], packet.sequence[1]],
+            entrance,
+            remote: packet.sequence[3],
+            local: packet.sequence[2],
+            state: CallState::Ringing
+        }
+    }
+
+    fn belongs(&self, packet: &CtppPacket) -> bool {
+        packet.sequence[0..2] == self.exchange
+    }
+
+    fn is_over(&self) -> bool {
+        matches!(self.state, CallState::Ended | CallState::Missed)
+    }
+
+    // Returns the state the panel moved the call to, if it did
+    fn receive(&mut self, packet: &CtppPacket) -> Option<CallState> {
+        self.remote = packet.sequence[3];
+
+        let hang_up = packet.kind == CtppPacketKind::Command &&
+            packet.body.starts_with(&HANGUP_ACTION);
+
+        self.state = match (self.state, &packet.kind) {
+            // The panel acknowledges the answer by sending our number back
+            (CallState::Answering, CtppPacketKind::Ack) if packet.sequence[2] == self.local =>
+                CallState::Connected,
+            (CallState::Ringing, _) if hang_up => CallState::Missed,
+            (CallState::Answering | CallState::Connected, _) if hang_up => CallState::Ended,
+            _ => return None
+        };
+
+        Some(self.state)
+    }
+
+    // Like any acknowledgement, it bumps our own number
+    fn ack(&mut self) -> [u8; 4] {
+        self.local = self.local.wrapping_add(1);
+        self.sequence()
+    }
+
+    // Returns the action to send to the panel
+    fn act(&mut self, action: CallAction) -> Result<[u8; 2], CallError> {
+        let (state, code) = match (self.state, action) {
+            (CallState::Ringing, CallAction::Answer) => (CallState::Answering, ANSWER_ACTION),
+            (CallState::Ringing, CallAction::Reject) => (CallState::Ended, HANGUP_ACTION),
+            (CallState::Answering | CallState::Connected, CallAction::HangUp) =>
+                (CallState::Ended, HANGUP_ACTION),
+            (state, _) => return Err(CallError::InvalidState(state))
+        };
+
+        self.state = state;
+        Ok(code)
+    }
+
+    fn sequence(&self) -> [u8; 4] {
+        [self.exchange[0], self.exchange[1], self.remote, self.local]
+    }
+}
+
+// Everything that's read from the CTPP channel of the apartment goes
+// through here, which picks out the rings and keeps track of the call
+// that follows. Every command the device sends is acknowledged, like
+// during a door opening. The panel presumably repeats a ring until then,
+// so only the first packet of an exchange counts.
+pub(crate) struct Calls {
+    ctpp_channel: CTPPChannel,
+    addr: String,
+    sub: String,
+    handshake_confirmed: bool,
+    call: Option<Call>
+}
+
+impl Calls {
+    pub fn new(ctpp_channel: CTPPChannel, addr: &str, sub: &str) -> Calls {
+        Calls {
+            ctpp_channel,
+            addr: addr.to_string(),
+            sub: sub.to_string(),
+            handshake_confirmed: false,
+            call: None
+        }
+    }
+
+    pub fn state(&self) -> Option<CallState> {
+        self.call.as_ref().map(|call| call.state)
+    }
+
+    // Returns the requests to write in response to the reply, next to
+    // the events that came out of it.
+    pub fn receive(&mut self, reply: &[u8]) -> (Vec<Vec<u8>>, Vec<Event>) {
+        self.ctpp_channel.receive(reply);
+
+        let mut requests = vec![];
+        let mut events = vec![];
+
+        // The handshake is confirmed the same way as when opening a door
+        if !self.handshake_confirmed && self.ctpp_channel.confirm_handshake(reply) {
+            self.handshake_confirmed = true;
+            requests.extend(self.ctpp_channel.handshake_acks(&self.sub, &self.addr));
+        }
+
+        for packet in CtppPacket::decode(reply).unwrap_or_default() {
+            let state = match &mut self.call {
+                Some(call) if call.belongs(&packet) => call.receive(&packet),
+                _ => {
+                    events.extend(self.ring(&packet));
+                    None
+                }
+            };
+
+            events.extend(state.map(Event::CallStateChanged));
+
+            if packet.kind == CtppPacketKind::Command {
+                requests.push(self.ack(&packet));
+            }
+        }
+
+        (requests, events)
+    }
+
+    // A command outside of the call is acknowledged with the numbers it
+    // came with, the same way a ring starts a call.
+    fn ack(&mut self, packet: &CtppPacket) -> Vec<u8> {
+        let (sequence, entrance) = match &mut self.call {
+            Some(call) if call.belongs(packet) => (call.ack(), call.entrance.clone()),
+            _ => {
+                let entrance = packet.actuators().map(|(entrance, _)| entrance);
+                let sequence = packet.sequence;
+
+                (
+                    [sequence[0], sequence[1], sequence[3], sequence[2].wrapping_add(1)],
+                    entrance.unwrap_or_default()
+                )
+            }
+        };
+
+        self.ctpp_channel.call_ack(sequence, &self.sub, &entrance)
+    }
+
+    // Returns the request to write to the CTPP channel
+    pub fn act(&mut self, action: CallAction) -> Result<Vec<u8>, CallError> {
+        let call = self.call.as_mut().ok_or(CallError::NoCall)?;
+        let code = call.act(action)?;
+
+        Ok(self.ctpp_channel.call_action(call.sequence(), code, &self.sub, &call.entrance))
+    }
+
+    fn ring(&mut self, packet: &CtppPacket) -> Option<Event> {
+        if packet.kind != CtppPacketKind::Command || !packet.body.starts_with(&RING_ACTION) {
+            return None
+        }
+
+        // The panel links itself to the apartment that it calls
+        let (entrance, apt_address) = packet.actuators()?;
+        if !apt_address.starts_with(&self.addr) {
+            return None
+        }
+
+        // Another entrance can't take over the call that's going on
+        if self.call.as_ref().is_some_and(|call| !call.is_over()) {
+            return None
+        }
+
+        self.call = Some(Call::ringing(packet, entrance.clone()));
+
+        Some(Event::IncomingCall {
+            entrance,
+            apt_address,
+            timestamp: SystemTime::now()
+        })
+    }
+}
+
+type SharedCalls = Arc<Mutex<Option<Calls>>>;
+
+fn lock(calls: &SharedCalls) -> MutexGuard<'_, Option<Calls>> {
+    calls.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Keeps the CTPP channel open for the apartment on a background thread
+/// and reports every incoming call, for as long as this handle is kept
+/// around.
+pub struct CallListener {
+    connection: Arc<Connection>,
+    calls: SharedCalls,
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl CallListener {
+    pub(crate) fn spawn<F>(connection: Arc<Connection>,
+                           vip: &VipResponse,
+                           mut on_event: F) -> CallListener
+        where F: FnMut(Event) + Send + 'static {
+
+        let (stop, stopped) = mpsc::channel();
+        let calls: SharedCalls = Arc::new(Mutex::new(None));
+        let addr = vip.apt_address.to_string();
+        let sub = format!("{}{}", addr, vip.apt_subaddress);
+
+        let thread = {
+            let connection = Arc::clone(&connection);
+            let calls = Arc::clone(&calls);
+
+            thread::spawn(move || {
+                let result = Self::run(&connection, &calls, &addr, &sub, &stopped, &mut on_event);
+
+                // Nothing can be sent to the panel without the channel
+                lock(&calls).take();
+
+                if let Err(e) = result {
+                    on_event(Event::Closed(e));
+                }
+            })
+        };
+
+        CallListener {
+            connection,
+            calls,
+            stop: Some(stop),
+            thread: Some(thread)
+        }
+    }
+
+    /// A handle to answer, reject or hang up the latest incoming call.
+    /// It keeps working for the calls after that, until the listener is
+    /// stopped.
+    pub fn call(&self) -> CallHandle {
+        CallHandle {
+            connection: Arc::clone(&self.connection),
+            calls: Arc::clone(&self.calls)
+        }
+    }
+
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    // Reads the CTPP channel until the listener is stopped. A read that
+    // times out only means nobody rang in the meantime.
+    fn run<F>(connection: &Connection,
+              calls: &SharedCalls,
+              addr: &String,
+              sub: &String,
+              stopped: &mpsc::Receiver<()>,
+              on_event: &mut F) -> Result<(), ViperError>
+        where F: FnMut(Event) {
+
+        let mut channel = connection.handle(ChannelKind::CTPP)?;
+        let ctpp_channel = CTPPChannel::new(&channel.control());
+
+        channel.open_with(&ctpp_channel.open(sub))?;
+        channel.write(&ctpp_channel.connect_hs(sub, addr))?;
+
+        *lock(calls) = Some(Calls::new(ctpp_channel, addr, sub));
+
+        loop {
+            match stopped.try_recv() {
+                Err(TryRecvError::Empty) => {},
+                // The CTPP channel is closed when the handle drops
+                _ => return Ok(())
+            }
+
+            let reply = match channel.read() {
+                Ok(reply) => reply,
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => return Err(ViperError::from(e))
+            };
+
+            // Not locked while the events are handled, which might
+            // want to answer the call.
+            let (requests, events) = match lock(calls).as_mut() {
+                Some(calls) => calls.receive(&reply),
+                None => (vec![], vec![])
+            };
+
+            for request in requests {
+                channel.write(&request)?;
+            }
+
+            for event in events {
+                on_event(event);
+            }
+        }
+    }
+
+    fn shutdown(&mut self) {
+        // Dropping the sender stops the thread after its current read
+        self.stop.take();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for CallListener {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Acts on the call that came in last on a [`CallListener`]. Like the
+/// client itself, it keeps the connection open until it's dropped.
+#[derive(Clone)]
+pub struct CallHandle {
+    connection: Arc<Connection>,
+    calls: SharedCalls
+}
+
+impl CallHandle {
+    /// `None` when nobody has rung yet
+    pub fn state(&self) -> Option<CallState> {
+        lock(&self.calls).as_ref().and_then(Calls::state)
+    }
+
+    /// The call is [`CallState::Answering`] until the panel acknowledges
+    /// the answer, which is reported as a [`CallState::Connected`] change.
+    pub fn answer(&self) -> Result<(), ViperError> {
+        self.act(CallAction::Answer)
+    }
+
+    pub fn reject(&self) -> Result<(), ViperError> {
+        self.act(CallAction::Reject)
+    }
+
+    pub fn hang_up(&self) -> Result<(), ViperError> {
+        self.act(CallAction::HangUp)
+    }
+
+    fn act(&self, action: CallAction) -> Result<(), ViperError> {
+        let request = lock(&self.calls)
+            .as_mut()
+            .ok_or(CallError::NoCall)?
+            .act(action)?;
+
+        self.connection.stream.write(&request)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::ViperClient;
+    use crate::command_response::ConfigurationResponse;
+    use crate::frame::Frame;
+    use crate::test_helper::{
+        call_packet,
+        confirm,
+        ring,
+        SimpleTcpListener,
+        CONFIGURATION_RESPONSE,
+        OPENED
+    };
+
+    #[test]
+    fn test_listen() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3361");
+        let client = ViperClient::connect("127.0.0.1", "3361").unwrap();
+
+        let server = thread::spawn(move || {
+            // Someone rings right after the handshake
+            listener.serve(|request| {
+                match request.payload[0] {
+                    0xcd => vec![OPENED.to_vec()],
+                    0xc0 => vec![
+                        confirm(0x60, &request.payload),
+                        ring([0x12, 0x34, 0x00, 0x01], "SB100001", "SB000006")
+                    ],
+                    0xef => vec![vec![]],
+                    _ => vec![]
+                }
+            })
+        });
+
+        let config: ConfigurationResponse = serde_json::from_str(CONFIGURATION_RESPONSE).unwrap();
+        let (events, received) = mpsc::channel();
+        let calls = client.listen(
+            &config.vip,
+            move |event| { let _ = events.send(event); }
+        );
+
+        match received.recv_timeout(Duration::from_secs(1)) {
+            Ok(Event::IncomingCall { entrance, apt_address, .. }) => {
+                assert_eq!(entrance, "SB100001");
+                assert_eq!(apt_address, "SB000006");
+            },
+            other => panic!("Expected an incoming call, got {:?}", other)
+        }
+
+        calls.stop();
+        drop(client);
+
+        let requests = server.join().unwrap();
+        assert_eq!(&requests[0].payload[8..12], b"CTPP");
+        assert_eq!(&requests.last().unwrap().payload[0..2], &[0xef, 0x01]);
+
+        // The ring was acknowledged
+        let ack = requests
+            .iter()
+            .find(|request| request.payload[0..4] == [0x00, 0x18, 0x12, 0x34])
+            .unwrap();
+        let packets = CtppPacket::decode(&ack.payload).unwrap();
+        assert_eq!(packets[0].kind, CtppPacketKind::Ack);
+        assert_eq!(packets[0].sequence, [0x12, 0x34, 0x01, 0x01]);
+    }
+
+    #[test]
+    fn test_listen_acks_handshake() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3372");
+        let client = ViperClient::connect("127.0.0.1", "3372").unwrap();
+
+        let server = thread::spawn(move || {
+            // The ring only comes in after the handshake is acknowledged
+            listener.serve(|request| {
+                match request.payload[0] {
+                    0xcd => vec![OPENED.to_vec()],
+                    0xc0 => vec![confirm(0x60, &request.payload)],
+                    0x20 => vec![ring([0x12, 0x34, 0x00, 0x01], "SB100001", "SB000006")],
+                    0xef => vec![vec![]],
+                    _ => vec![]
+                }
+            })
+        });
+
+        let config: ConfigurationResponse = serde_json::from_str(CONFIGURATION_RESPONSE).unwrap();
+        let (events, received) = mpsc::channel();
+        let calls = client.listen(
+            &config.vip,
+            move |event| { let _ = events.send(event); }
+        );
+
+        assert!(matches!(
+            received.recv_timeout(Duration::from_secs(1)),
+            Ok(Event::IncomingCall { .. })
+        ));
+
+        calls.stop();
+        drop(client);
+
+        // Both acknowledgements follow the handshake, like when opening
+        // a door, and carry the number the device replied with.
+        let requests = server.join().unwrap();
+        let handshake = &requests[1].payload;
+        let reply = confirm(0x60, handshake);
+
+        assert_eq!(handshake[0], 0xc0);
+        assert_eq!(requests[2].payload[0], 0x00);
+        assert_eq!(requests[3].payload[0], 0x20);
+        assert_eq!(requests[2].payload[4], reply[5]);
+        assert_eq!(requests[3].payload[4], reply[5]);
+    }
+
+    #[test]
+    fn test_answer() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3362");
+        let client = ViperClient::connect("127.0.0.1", "3362").unwrap();
+
+        let server = thread::spawn(move || {
+            // The panel acknowledges the answer and hangs up right away
+            listener.serve(|request| {
+                let payload = &request.payload;
+
+                match (payload[0], &payload[8..10]) {
+                    (0xcd, _) => vec![OPENED.to_vec()],
+                    (0xc0, [0x00, 0x40]) => vec![
+                        confirm(0x60, payload),
+                        ring([0x12, 0x34, 0x00, 0x01], "SB100001", "SB000006")
+                    ],
+                    (0xc0, [0x00, 0x21]) => vec![
+                        vec![0x00, 0x18, 0x12, 0x34, 0x01, 0x02, 0x00, 0x00],
+                        call_packet(HANGUP_ACTION, [0x12, 0x34, 0x01, 0x03], "SB100001", "SB000006")
+                    ],
+                    (0xef, _) => vec![vec![]],
+                    _ => vec![]
+                }
+            })
+        });
+
+        let config: ConfigurationResponse = serde_json::from_str(CONFIGURATION_RESPONSE).unwrap();
+        let (events, received) = mpsc::channel();
+        let calls = client.listen(
+            &config.vip,
+            move |event| { let _ = events.send(event); }
+        );
+        let call = calls.call();
+        let timeout = Duration::from_secs(1);
+
+        assert!(matches!(received.recv_timeout(timeout), Ok(Event::IncomingCall { .. })));
+        assert_eq!(call.state(), Some(CallState::Ringing));
+
+        call.answer().unwrap();
+        assert!(matches!(
+            received.recv_timeout(timeout),
+            Ok(Event::CallStateChanged(CallState::Connected))
+        ));
+        assert!(matches!(
+            received.recv_timeout(timeout),
+            Ok(Event::CallStateChanged(CallState::Ended))
+        ));
+        assert_eq!(call.state(), Some(CallState::Ended));
+
+        calls.stop();
+        assert!(matches!(call.hang_up(), Err(ViperError::Call(CallError::NoCall))));
+
+        // The handle keeps the connection around as well
+        drop(call);
+        drop(client);
+
+        let requests = server.join().unwrap();
+        let answer = requests
+            .iter()
+            .find(|request| request.payload[8..10] == ANSWER_ACTION)
+            .unwrap();
+
+        assert_eq!(&answer.payload[2..6], &[0x12, 0x34, 0x01, 0x01]);
+    }
+
+    #[test]
+    fn test_answer_during_keepalive() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3373");
+        let client = ViperClient::connect("127.0.0.1", "3373").unwrap();
+
+        // The panel only acknowledges the answer while the ECHO channel
+        // is being opened, and hangs up while it's being closed.
+        let server = thread::spawn(move || {
+            let mut ctpp = [0, 0];
+
+            listener.serve_frames(|request| {
+                let payload = &request.payload;
+                let closed = Frame::new([0, 0], vec![]);
+
+                match (payload[0], &payload[8..10]) {
+                    (0xcd, b"CT") => vec![Frame::new([0, 0], OPENED.to_vec())],
+                    (0xcd, _) => vec![
+                        Frame::new(ctpp, vec![0x00, 0x18, 0x12, 0x34, 0x01, 0x02, 0x00, 0x00]),
+                        Frame::new([0, 0], OPENED.to_vec())
+                    ],
+                    (0xc0, [0x00, 0x40]) => {
+                        ctpp = request.channel;
+
+                        vec![
+                            Frame::new(ctpp, confirm(0x60, payload)),
+                            Frame::new(ctpp, ring([0x12, 0x34, 0x00, 0x01], "SB100001", "SB000006"))
+                        ]
+                    },
+                    (0xef, _) if payload[8..10] != ctpp => vec![
+                        closed,
+                        Frame::new(
+                            ctpp,
+                            call_packet(HANGUP_ACTION, [0x12, 0x34, 0x01, 0x03], "SB100001", "SB000006")
+                        )
+                    ],
+                    (0xef, _) => vec![closed],
+                    _ => vec![]
+                }
+            })
+        });
+
+        let config: ConfigurationResponse = serde_json::from_str(CONFIGURATION_RESPONSE).unwrap();
+        let (events, received) = mpsc::channel();
+        let calls = client.listen(
+            &config.vip,
+            move |event| { let _ = events.send(event); }
+        );
+        let call = calls.call();
+        let timeout = Duration::from_secs(1);
+
+        assert!(matches!(received.recv_timeout(timeout), Ok(Event::IncomingCall { .. })));
+
+        call.answer().unwrap();
+        client.keepalive(Duration::from_secs(60), |_| {}).stop();
+
+        assert!(matches!(
+            received.recv_timeout(timeout),
+            Ok(Event::CallStateChanged(CallState::Connected))
+        ));
+        assert!(matches!(
+            received.recv_timeout(timeout),
+            Ok(Event::CallStateChanged(CallState::Ended))
+        ));
+
+        calls.stop();
+        drop(call);
+        drop(client);
+
+        let requests = server.join().unwrap();
+        assert!(requests.iter().any(|request| &request.payload[8..12] == b"ECHO"));
+    }
+
+    fn calls() -> Calls {
+        Calls::new(CTPPChannel::new(&[1, 2]), "SB000006", "SB0000062")
+    }
+
+    // Feeds every packet and returns the events that came out of each
+    fn replay(calls: &mut Calls, packets: &[Vec<u8>]) -> Vec<Vec<Event>> {
+        packets.iter().map(|packet| calls.receive(packet).1).collect()
+    }
+
+    fn acked(request: &[u8]) -> [u8; 4] {
+        let packets = CtppPacket::decode(&request[8..]).unwrap();
+        assert_eq!(packets[0].kind, CtppPacketKind::Ack);
+
+        packets[0].sequence
+    }
+
+    #[test]
+    fn test_rings() {
+        let mut calls = calls();
+        let packet = ring([0x12, 0x34, 0x00, 0x01], "SB100001", "SB000006");
+
+        let (requests, events) = calls.receive(&packet);
+        match &events[..] {
+            [Event::IncomingCall { entrance, apt_address, .. }] => {
+                assert_eq!(entrance, "SB100001");
+                assert_eq!(apt_address, "SB000006");
+            },
+            other => panic!("Expected an incoming call, got {:?}", other)
+        }
+        assert_eq!(acked(&requests[0]), [0x12, 0x34, 0x01, 0x01]);
+
+        // The same ring again, and a ring for the neighbours, are only
+        // acknowledged.
+        let (requests, events) = calls.receive(&packet);
+        assert!(events.is_empty());
+        assert_eq!(acked(&requests[0]), [0x12, 0x34, 0x01, 0x02]);
+
+        let (requests, events) = calls.receive(&ring([0x56, 0x78, 0x00, 0x01], "SB100001", "SB000007"));
+        assert!(events.is_empty());
+        assert_eq!(acked(&requests[0]), [0x56, 0x78, 0x01, 0x01]);
+        assert_eq!(calls.state(), Some(CallState::Ringing));
+    }
+
+    #[test]
+    fn test_ring_during_call() {
+        let mut calls = calls();
+        let other = ring([0x56, 0x78, 0x00, 0x01], "SB100002", "SB000006");
+
+        calls.receive(&ring([0x12, 0x34, 0x00, 0x01], "SB100001", "SB000006"));
+        assert!(calls.receive(&other).1.is_empty());
+
+        calls.act(CallAction::Answer).unwrap();
+        assert!(calls.receive(&other).1.is_empty());
+
+        // The call still goes to the first entrance
+        let hang_up = calls.act(CallAction::HangUp).unwrap();
+        let packets = CtppPacket::decode(&hang_up[8..]).unwrap();
+        assert_eq!(&packets[0].sequence[0..2], &[0x12, 0x34]);
+        assert_eq!(calls.state(), Some(CallState::Ended));
+
+        // Once it's over the next ring comes through
+        assert!(matches!(&calls.receive(&other).1[..], [Event::IncomingCall { .. }]));
+    }
+
+    #[test]
+    fn test_ignores_other_packets() {
+        let mut calls = calls();
+        let ack = [0x00, 0x18, 0x12, 0x34, 0x00, 0x01, 0x00, 0x00];
+
+        let (requests, events) = calls.receive(&ack);
+        assert!(requests.is_empty());
+        assert!(events.is_empty());
+        assert!(calls.receive(&[0xc0, 0x18, 0x12]).1.is_empty());
+        assert_eq!(calls.state(), None);
+        assert_eq!(calls.act(CallAction::Answer), Err(CallError::NoCall));
+    }
+
+    #[test]
+    fn test_missed_call() {
+        let mut calls = calls();
+        let events = replay(&mut calls, &[
+            ring([0x12, 0x34, 0x00, 0x01], "SB100001", "SB000006"),
+            ring([0x12, 0x34, 0x00, 0x02], "SB100001", "SB000006"),
+            call_packet(HANGUP_ACTION, [0x12, 0x34, 0x00, 0x03], "SB100001", "SB000006")
+        ]);
+
+        assert!(matches!(&events[0][..], [Event::IncomingCall { .. }]));
+        assert!(events[1].is_empty());
+        assert!(matches!(&events[2][..], [Event::CallStateChanged(CallState::Missed)]));
+        assert_eq!(
+            calls.act(CallAction::Answer),
+            Err(CallError::InvalidState(CallState::Missed))
+        );
+    }
+
+    #[test]
+    fn test_answer_and_hang_up() {
+        let mut calls = calls();
+        calls.receive(&ring([0x12, 0x34, 0x00, 0x01], "SB100001", "SB000006"));
+
+        let answer = calls.act(CallAction::Answer).unwrap();
+        let packets = CtppPacket::decode(&answer[8..]).unwrap();
+        assert_eq!(packets[0].sequence, [0x12, 0x34, 0x01, 0x01]);
+        assert_eq!(&packets[0].body[0..2], &ANSWER_ACTION);
+        assert_eq!(calls.state(), Some(CallState::Answering));
+
+        // An acknowledgement of something else doesn't connect the call
+        assert!(calls.receive(&[0x00, 0x18, 0x12, 0x34, 0x00, 0x02, 0x00, 0x00]).1.is_empty());
+        assert_eq!(calls.state(), Some(CallState::Answering));
+
+        // The panel acknowledges, then we hang up ourselves
+        let events = calls.receive(&[0x00, 0x18, 0x12, 0x34, 0x01, 0x02, 0x00, 0x00]).1;
+        assert!(matches!(&events[..], [Event::CallStateChanged(CallState::Connected)]));
+
+        let hang_up = calls.act(CallAction::HangUp).unwrap();
+        let packets = CtppPacket::decode(&hang_up[8..]).unwrap();
+        assert_eq!(packets[0].sequence, [0x12, 0x34, 0x02, 0x01]);
+        assert_eq!(&packets[0].body[0..2], &HANGUP_ACTION);
+        assert_eq!(calls.state(), Some(CallState::Ended));
+    }
+
+    #[test]
+    fn test_panel_hangs_up() {
+        let mut calls = calls();
+        let ring = ring([0x12, 0x34, 0x00, 0x01], "SB100001", "SB000006");
+
+        calls.receive(&ring);
+        calls.act(CallAction::Answer).unwrap();
+
+        let events = replay(&mut calls, &[
+            call_packet(HANGUP_ACTION, [0x12, 0x34, 0x01, 0x02], "SB100001", "SB000006"),
+            // A ring of the next call
+            ring.iter().enumerate().map(|(i, b)| if i == 2 { 0x13 } else { *b }).collect()
+        ]);
+
+        assert!(matches!(&events[0][..], [Event::CallStateChanged(CallState::Ended)]));
+        assert!(matches!(&events[1][..], [Event::IncomingCall { .. }]));
+        assert_eq!(calls.state(), Some(CallState::Ringing));
+    }
+
+    #[test]
+    fn test_reject() {
+        let mut calls = calls();
+        calls.receive(&ring([0x12, 0x34, 0x00, 0x01], "SB100001", "SB000006"));
+
+        let reject = calls.act(CallAction::Reject).unwrap();
+        let packets = CtppPacket::decode(&reject[8..]).unwrap();
+        assert_eq!(&packets[0].body[0..2], &HANGUP_ACTION);
+        assert_eq!(calls.state(), Some(CallState::Ended));
+        assert_eq!(
+            calls.act(CallAction::HangUp),
+            Err(CallError::InvalidState(CallState::Ended))
+        );
+    }
+}
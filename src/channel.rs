@@ -1,38 +1,394 @@
+use std::{fmt, io};
 use crate::command::{Command, CommandKind};
+use crate::command_response::{ChannelOpened, ResponseHeader};
+use crate::connection::Connection;
+use crate::frame::FrameError;
+use crate::stream_wrapper::StreamWrapper;
+use crate::ViperError;
+
+/// Every channel that can be opened on the device, see docs/channels.md
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChannelKind {
+    CSPB,
+    CTPP,
+    ECHO,
+    #[allow(non_camel_case_types)]
+    ECHO_SRV,
+    FRCG,
+    INFO,
+    PUSH,
+    RTPC,
+    UAUT,
+    UADM,
+    UCFG,
+    UDPM,
+    FACT
+}
+
+impl ChannelKind {
+    pub const ALL: [ChannelKind; 13] = [
+        ChannelKind::CSPB,
+        ChannelKind::CTPP,
+        ChannelKind::ECHO,
+        ChannelKind::ECHO_SRV,
+        ChannelKind::FRCG,
+        ChannelKind::INFO,
+        ChannelKind::PUSH,
+        ChannelKind::RTPC,
+        ChannelKind::UAUT,
+        ChannelKind::UADM,
+        ChannelKind::UCFG,
+        ChannelKind::UDPM,
+        ChannelKind::FACT
+    ];
+
+    /// The name the channel is opened with
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChannelKind::CSPB => "CSPB",
+            ChannelKind::CTPP => "CTPP",
+            ChannelKind::ECHO => "ECHO",
+            ChannelKind::ECHO_SRV => "ECHO_SRV",
+            ChannelKind::FRCG => "FRCG",
+            ChannelKind::INFO => "INFO",
+            ChannelKind::PUSH => "PUSH",
+            ChannelKind::RTPC => "RTPC",
+            ChannelKind::UAUT => "UAUT",
+            ChannelKind::UADM => "UADM",
+            ChannelKind::UCFG => "UCFG",
+            ChannelKind::UDPM => "UDPM",
+            ChannelKind::FACT => "FACT"
+        }
+    }
+
+    /// The capability the device lists in its INFO response when it
+    /// supports the channel. Channels without one aren't advertised.
+    pub fn capability(&self) -> Option<&'static str> {
+        match self {
+            ChannelKind::UAUT => Some("user-auth-channel"),
+            ChannelKind::UADM => Some("user-admin-channel"),
+            ChannelKind::UCFG => Some("configuration-channel"),
+            ChannelKind::PUSH => Some("push-notifications-channel"),
+            ChannelKind::FACT => Some("fast-activation-channel"),
+            ChannelKind::FRCG => Some("face-recognition-channel"),
+            _ => None
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ChannelKind> {
+        Self::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+
+    pub fn from_capability(capability: &str) -> Option<ChannelKind> {
+        Self::ALL.into_iter().find(|kind| kind.capability() == Some(capability))
+    }
+}
+
+impl fmt::Display for ChannelKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
 
 pub struct Channel {
-    command: String,
-    control: [u8; 2]
+    kind: ChannelKind,
+    pub control: [u8; 2]
 }
 
 impl Channel {
-    pub fn new(control: &[u8; 2], command: &'static str) -> Channel {
+    pub fn new(control: &[u8; 2], kind: ChannelKind) -> Channel {
         Channel {
             control: *control,
-            command: command.to_string()
+            kind
         }
     }
 
     pub fn open(&self) -> Vec<u8> {
-        Command::channel(&self.command, &self.control, None)
+        Command::channel(&self.kind.to_string(), &self.control, None)
+    }
+
+    pub fn kind(&self) -> ChannelKind {
+        self.kind
     }
 
     pub fn close(&self) -> Vec<u8> {
         Command::close(&self.control)
     }
 
-    pub fn com(&self, kind: CommandKind) -> Vec<u8> {
-        Command::for_kind(kind, &self.control)
+    pub fn com(&self, kind: CommandKind, message_id: u32) -> Result<Vec<u8>, FrameError> {
+        Command::for_kind(kind, message_id, &self.control)
+    }
+}
+
+/// A channel that is open on the device. The channel is closed again
+/// when the handle goes out of scope, use [`ChannelHandle::close`] to
+/// find out whether closing it succeeded.
+pub struct ChannelHandle<'a> {
+    connection: &'a Connection,
+    channel: Channel,
+    open: bool,
+    allocated: bool
+}
+
+impl<'a> ChannelHandle<'a> {
+    // The control bytes of the channel have to be allocated already.
+    // Nothing is sent until `open` or `open_with` is called, and the
+    // channel is only closed on drop once the device has acknowledged
+    // opening it. The control bytes are released either way.
+    pub(crate) fn new(connection: &'a Connection, channel: Channel) -> ChannelHandle<'a> {
+        ChannelHandle {
+            connection,
+            channel,
+            open: false,
+            allocated: true
+        }
+    }
+
+    pub fn control(&self) -> [u8; 2] {
+        self.channel.control
+    }
+
+    pub(crate) fn open(&mut self) -> Result<ChannelOpened, ViperError> {
+        let open = self.channel.open();
+        self.open_with(&open)
+    }
+
+    // Opens the channel with a custom open request, like the CTPP channel
+    // which passes the apartment address along.
+    pub(crate) fn open_with(&mut self, request: &[u8]) -> Result<ChannelOpened, ViperError> {
+        // There's nothing to close on the device unless it acknowledged
+        // the request, so any failure only gives the control bytes back.
+        let reply = match self.stream().execute(request) {
+            Ok(reply) => reply,
+            Err(e) => {
+                self.release();
+                return Err(e.into())
+            }
+        };
+
+        match ChannelOpened::parse(&reply) {
+            Some(opened) => {
+                self.open = true;
+                Ok(opened)
+            },
+            None => {
+                self.release();
+                Err(ViperError::ChannelRefused(self.channel.kind(), reply))
+            }
+        }
+    }
+
+    pub fn com(&self, kind: CommandKind) -> Result<Vec<u8>, ViperError> {
+        let message = kind.message();
+        self.exchange(message, |message_id| self.channel.com(kind, message_id))
+    }
+
+    // Every command gets the next message id of the connection, and
+    // the response has to refer back to both the message and the id.
+    pub fn exchange<F>(&self, message: &str, command: F) -> Result<Vec<u8>, ViperError>
+        where F: FnOnce(u32) -> Result<Vec<u8>, FrameError> {
+
+        let message_id = self.connection.next_message_id();
+        let bytes = self.stream().execute(&command(message_id)?)?;
+
+        ResponseHeader::verify(&bytes, message, message_id)?;
+        Ok(bytes)
+    }
+
+    pub fn write(&self, b: &[u8]) -> Result<(), io::Error> {
+        self.stream().write(b)
+    }
+
+    pub fn read(&self) -> Result<Vec<u8>, io::Error> {
+        self.stream().read(&self.channel.control)
+    }
+
+    // Drops whatever is still queued for the channel, like late replies
+    // to a request that already timed out.
+    pub(crate) fn discard(&self) {
+        self.stream().discard(&self.channel.control)
+    }
+
+    pub fn close(mut self) -> Result<(), ViperError> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Result<(), ViperError> {
+        let result = match self.open {
+            true => self.stream().execute(&self.channel.close()).map(|_| ()),
+            false => Ok(())
+        };
+
+        self.open = false;
+        self.release();
+
+        result?;
+        Ok(())
+    }
+
+    // Whatever the device replied, nothing on this channel is going
+    // to be read anymore, so its control bytes can be reused.
+    fn release(&mut self) {
+        if !self.allocated {
+            return
+        }
+
+        self.allocated = false;
+        self.stream().discard(&self.channel.control);
+        self.connection.allocator().release(&self.channel.control);
+    }
+
+    fn stream(&self) -> &'a StreamWrapper {
+        &self.connection.stream
+    }
+}
+
+impl Drop for ChannelHandle<'_> {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::thread;
+    use std::time::Duration;
+    use crate::stream_wrapper::Timeouts;
+    use crate::test_helper::{open_ack, SimpleTcpListener, OPENED};
 
     #[test]
     fn test_channel_open() {
-        let channel = Channel::new(&[0, 0], "INFO");
+        let channel = Channel::new(&[0, 0], ChannelKind::INFO);
         channel.open();
     }
+
+    #[test]
+    fn test_channel_kind_names() {
+        for kind in ChannelKind::ALL {
+            assert_eq!(ChannelKind::from_name(kind.name()), Some(kind));
+        }
+
+        assert_eq!(ChannelKind::ECHO_SRV.to_string(), "ECHO_SRV");
+        assert_eq!(ChannelKind::from_name("NOPE"), None);
+    }
+
+    #[test]
+    fn test_channel_kind_capabilities() {
+        assert_eq!(ChannelKind::UAUT.capability(), Some("user-auth-channel"));
+        assert_eq!(ChannelKind::INFO.capability(), None);
+        assert_eq!(
+            ChannelKind::from_capability("face-recognition-channel"),
+            Some(ChannelKind::FRCG)
+        );
+        assert_eq!(ChannelKind::from_capability("cloudnext-device"), None);
+    }
+
+    #[test]
+    fn test_handle_closes_on_drop() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3345");
+        let stream = StreamWrapper::connect(
+            "127.0.0.1:3345",
+            &Timeouts::default()
+        ).unwrap();
+
+        let server = thread::spawn(move || {
+            listener.mock_replies(vec![&OPENED, &[]]).unwrap()
+        });
+
+        let connection = Connection::new(stream);
+        let control = {
+            let handle = connection.channel(ChannelKind::INFO).unwrap();
+            handle.control()
+        };
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests[1].payload, Command::close(&control)[8..]);
+        assert!(connection.allocator().open_channels().is_empty());
+    }
+
+    #[test]
+    fn test_failed_close_releases_channel() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3363");
+        let stream = StreamWrapper::connect(
+            "127.0.0.1:3363",
+            &Timeouts::default()
+        ).unwrap();
+
+        // Hangs up instead of answering the close
+        let server = thread::spawn(move || {
+            listener.mock_replies(vec![&OPENED]).unwrap()
+        });
+
+        let connection = Connection::new(stream);
+        let handle = connection.channel(ChannelKind::INFO).unwrap();
+        server.join().unwrap();
+
+        assert!(handle.close().is_err());
+        assert!(connection.allocator().open_channels().is_empty());
+    }
+
+    #[test]
+    fn test_failed_open_is_not_closed() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3370");
+        let timeouts = Timeouts {
+            read: Duration::from_millis(100),
+            ..Timeouts::default()
+        };
+        let stream = StreamWrapper::connect("127.0.0.1:3370", &timeouts).unwrap();
+
+        // Never answers the open
+        let server = thread::spawn(move || listener.serve(|_| vec![]));
+
+        let connection = Connection::new(stream);
+        {
+            let mut handle = connection.handle(ChannelKind::INFO).unwrap();
+            assert!(matches!(handle.open(), Err(ViperError::IOError(_))));
+        }
+
+        assert!(connection.allocator().open_channels().is_empty());
+        drop(connection);
+
+        // Only the open request was sent
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_concurrent_opens() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3371");
+        let stream = StreamWrapper::connect(
+            "127.0.0.1:3371",
+            &Timeouts::default()
+        ).unwrap();
+
+        // Every open is acknowledged with the control bytes it asked for
+        // as the device's end, so each reply can be traced back to its
+        // request. Closes get an empty reply.
+        let server = thread::spawn(move || listener.serve(|request| {
+            let payload = &request.payload;
+
+            match payload[0..2] {
+                [0xcd, 0xab] => vec![open_ack(&payload[payload.len() - 3..payload.len() - 1])],
+                _ => vec![vec![]]
+            }
+        }));
+
+        let connection = Connection::new(stream);
+        thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    for _ in 0..50 {
+                        let mut handle = connection.handle(ChannelKind::INFO).unwrap();
+                        let opened = handle.open().unwrap();
+
+                        assert_eq!(opened.server_channel, handle.control());
+                        handle.close().unwrap();
+                    }
+                });
+            }
+        });
+
+        drop(connection);
+        assert_eq!(server.join().unwrap().len(), 200);
+    }
 }
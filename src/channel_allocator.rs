@@ -0,0 +1,110 @@
+use std::collections::BTreeSet;
+use crate::helper::{Helper, START, END};
+use crate::ViperError;
+
+/// Hands out the control bytes for new channels and keeps track of
+/// which channels are still open.
+///
+/// The first control byte is bumped by one for every channel that is
+/// opened, like the Comelit app does, and wraps around within the same
+/// range as [`Helper::control`] seeds it in. Bytes that belong to a
+/// channel which is still open are skipped.
+#[derive(Debug)]
+pub struct ChannelAllocator {
+    next: u8,
+    suffix: u8,
+    open: BTreeSet<[u8; 2]>
+}
+
+impl ChannelAllocator {
+    pub fn new() -> ChannelAllocator {
+        let [next, suffix] = Helper::control();
+
+        ChannelAllocator {
+            next,
+            suffix,
+            open: BTreeSet::new()
+        }
+    }
+
+    pub fn allocate(&mut self) -> Result<[u8; 2], ViperError> {
+        for _ in START..END {
+            let control = [self.next, self.suffix];
+            self.next = if self.next + 1 >= END { START } else { self.next + 1 };
+
+            if self.open.insert(control) {
+                return Ok(control)
+            }
+        }
+
+        Err(ViperError::NoChannelsAvailable)
+    }
+
+    // Returns false when the channel wasn't open to begin with
+    pub fn release(&mut self, control: &[u8; 2]) -> bool {
+        self.open.remove(control)
+    }
+
+    pub fn open_channels(&self) -> Vec<[u8; 2]> {
+        self.open.iter().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate() {
+        let mut allocator = ChannelAllocator::new();
+        let first = allocator.allocate().unwrap();
+        let second = allocator.allocate().unwrap();
+
+        assert_eq!(first[1], second[1]);
+        assert_ne!(first, second);
+        assert_eq!(allocator.open_channels().len(), 2);
+    }
+
+    #[test]
+    fn test_allocate_wraps_around() {
+        let mut allocator = ChannelAllocator {
+            next: END - 1,
+            suffix: 0x20,
+            open: BTreeSet::new()
+        };
+
+        assert_eq!(allocator.allocate().unwrap(), [END - 1, 0x20]);
+        assert_eq!(allocator.allocate().unwrap(), [START, 0x20]);
+    }
+
+    #[test]
+    fn test_allocate_skips_open_channels() {
+        let mut allocator = ChannelAllocator {
+            next: 0x10,
+            suffix: 0x20,
+            open: BTreeSet::from([[0x10, 0x20], [0x11, 0x20]])
+        };
+
+        assert_eq!(allocator.allocate().unwrap(), [0x12, 0x20]);
+    }
+
+    #[test]
+    fn test_allocate_exhausted() {
+        let mut allocator = ChannelAllocator::new();
+
+        for _ in START..END {
+            allocator.allocate().unwrap();
+        }
+
+        assert!(matches!(
+            allocator.allocate(),
+            Err(ViperError::NoChannelsAvailable)
+        ));
+
+        // Closing a single channel makes room for another one
+        let control = allocator.open_channels()[0];
+        assert!(allocator.release(&control));
+        assert!(!allocator.open_channels().contains(&control));
+        assert_eq!(allocator.allocate().unwrap(), control);
+    }
+}
@@ -0,0 +1,109 @@
+use std::time::Duration;
+use crate::stream_wrapper::{StreamWrapper, Timeouts};
+use crate::{ViperClient, ViperError};
+
+/// Configures and opens a connection to the doorbell:
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use viper_client::ViperClientBuilder;
+///
+/// let client = ViperClientBuilder::new("192.168.1.2", "64100")
+///     .connect_timeout(Duration::from_secs(2))
+///     .read_timeout(Duration::from_millis(1500))
+///     .connect();
+/// ```
+#[derive(Debug, Clone)]
+pub struct ViperClientBuilder {
+    ip: String,
+    port: String,
+    timeouts: Timeouts
+}
+
+impl ViperClientBuilder {
+    pub fn new(ip: &str, port: &str) -> ViperClientBuilder {
+        ViperClientBuilder {
+            ip: ip.to_string(),
+            port: port.to_string(),
+            timeouts: Timeouts::default()
+        }
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> ViperClientBuilder {
+        self.timeouts.connect = timeout;
+        self
+    }
+
+    pub fn read_timeout(mut self, timeout: Duration) -> ViperClientBuilder {
+        self.timeouts.read = timeout;
+        self
+    }
+
+    pub fn write_timeout(mut self, timeout: Duration) -> ViperClientBuilder {
+        self.timeouts.write = timeout;
+        self
+    }
+
+    pub fn connect(&self) -> Result<ViperClient, ViperError> {
+        let stream = StreamWrapper::connect(&self.address(), &self.timeouts)
+            .map_err(ViperError::ConnectError)?;
+
+        Ok(ViperClient::from_stream(stream))
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn connect_async(&self) -> Result<crate::r#async::ViperClient, ViperError> {
+        use crate::async_stream_wrapper::AsyncStreamWrapper;
+
+        let stream = AsyncStreamWrapper::connect(&self.address(), &self.timeouts)
+            .await
+            .map_err(ViperError::ConnectError)?;
+
+        Ok(crate::r#async::ViperClient::from_stream(stream))
+    }
+
+    fn address(&self) -> String {
+        format!("{}:{}", self.ip, self.port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_timeouts() {
+        let builder = ViperClientBuilder::new("127.0.0.1", "3343")
+            .connect_timeout(Duration::from_millis(10))
+            .read_timeout(Duration::from_millis(20))
+            .write_timeout(Duration::from_millis(30));
+
+        assert_eq!(builder.timeouts.connect, Duration::from_millis(10));
+        assert_eq!(builder.timeouts.read, Duration::from_millis(20));
+        assert_eq!(builder.timeouts.write, Duration::from_millis(30));
+    }
+
+    #[test]
+    fn test_connect_error() {
+        // Grab a free port and release it again, so nothing is
+        // listening on the other end.
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+
+        let result = ViperClientBuilder::new("127.0.0.1", &port.to_string())
+            .connect_timeout(Duration::from_millis(100))
+            .connect();
+
+        assert!(matches!(result, Err(ViperError::ConnectError(_))));
+    }
+
+    #[test]
+    fn test_connect_unresolvable() {
+        let result = ViperClientBuilder::new("not an ip", "3344").connect();
+
+        assert!(matches!(result, Err(ViperError::ConnectError(_))));
+    }
+}
@@ -1,15 +1,61 @@
+use std::fmt;
 use serde::Serialize;
+use serde_json::{json, Value};
+use crate::command_response::FaceRecognitionParams;
+use crate::frame::{Frame, FrameError};
+use crate::ViperError;
 
 const OPEN:  [u8; 8] = [0xcd, 0xab, 0x01, 0x00, 0x07, 0x00, 0x00, 0x00];
 const CLOSE: [u8; 8] = [0xef, 0x01, 0x03, 0x00, 0x02, 0x00, 0x00, 0x00];
 
+/// Which address books a UCFG request asks for. `All` is the one that
+/// includes the actuators, which are needed to open doors.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AddressBooks {
+    All,
+    None,
+    Custom(String)
+}
+
+impl fmt::Display for AddressBooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressBooks::All => write!(f, "all"),
+            AddressBooks::None => write!(f, "none"),
+            AddressBooks::Custom(addressbooks) => write!(f, "{}", addressbooks)
+        }
+    }
+}
+
 pub enum CommandKind {
     UAUT(String),
-    UCFG(String),
+    UCFG(AddressBooks),
     RemoveAllUsers(String),
     ActivateUser(String),
+    PushInfo(PushToken),
+    ListUsers,
+    RemoveUser(String),
     INFO,
-    FRCG
+    FRCG,
+    SetFaceRecognitionParams(FaceRecognitionParams)
+}
+
+impl CommandKind {
+    // The message a response to this command has to carry as well
+    pub fn message(&self) -> &'static str {
+        match self {
+            CommandKind::UAUT(_) => "access",
+            CommandKind::UCFG(_) => "get-configuration",
+            CommandKind::RemoveAllUsers(_) => "remove-all-users",
+            CommandKind::ActivateUser(_) => "activate-user",
+            CommandKind::PushInfo(_) => "push-info",
+            CommandKind::ListUsers => "list-users",
+            CommandKind::RemoveUser(_) => "remove-user",
+            CommandKind::INFO => "server-info",
+            CommandKind::FRCG => "rcg-get-params",
+            CommandKind::SetFaceRecognitionParams(_) => "rcg-set-params"
+        }
+    }
 }
 
 pub struct Command { }
@@ -19,12 +65,12 @@ pub struct Command { }
 struct Base {
     message: String,
     message_type: String,
-    message_id: u8
+    message_id: u32
 }
 
 impl Base {
-    pub fn request(message: &'static str, message_id: u8) -> Base {
-        return Base {
+    pub fn request(message: &'static str, message_id: u32) -> Base {
+        Base {
             message: String::from(message),
             message_type: String::from("request"),
             message_id
@@ -34,7 +80,7 @@ impl Base {
 
 #[derive(Serialize)]
 #[serde(rename_all = "kebab-case")]
-struct UAUT {
+struct Uaut {
     #[serde(flatten)]
     base: Base,
     user_token: String,
@@ -42,7 +88,7 @@ struct UAUT {
 
 #[derive(Serialize)]
 #[serde(rename_all = "kebab-case")]
-struct UCFG {
+struct Ucfg {
     #[serde(flatten)]
     base: Base,
     addressbooks: String
@@ -65,12 +111,59 @@ struct ActivateUser {
     description: String,
 }
 
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct RcgSetParams {
+    #[serde(flatten)]
+    base: Base,
+    #[serde(flatten)]
+    params: FaceRecognitionParams
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct RemoveUser {
+    #[serde(flatten)]
+    base: Base,
+    user_token: String,
+}
+
+/// Where the device sends its push notifications to. The apartment
+/// address and sub-address can be found in the `vip` section of the
+/// configuration.
+///
+/// The field names haven't been checked against a captured `push-info`
+/// request yet.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct PushToken {
+    pub apt_address: String,
+    pub apt_subaddress: u16,
+    pub bundle_id: String,
+    pub os_type: String,
+    pub profile_id: String,
+    pub device_token: String
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct PushInfo {
+    #[serde(flatten)]
+    base: Base,
+    #[serde(flatten)]
+    token: PushToken
+}
+
 impl Command {
-    pub fn for_kind(kind: CommandKind, control: &[u8]) -> Vec<u8> {
+    pub fn for_kind(kind: CommandKind,
+                    message_id: u32,
+                    control: &[u8]) -> Result<Vec<u8>, FrameError> {
+
+        let message = kind.message();
         let json = match kind {
             CommandKind::UAUT(token) => {
-                let uaut = UAUT {
-                    base: Base::request("access", 1),
+                let uaut = Uaut {
+                    base: Base::request(message, message_id),
                     user_token: token
                 };
 
@@ -78,29 +171,53 @@ impl Command {
             },
 
             CommandKind::UCFG(addressbooks) => {
-                let ucfg = UCFG {
-                    base: Base::request("get-configuration", 2),
-                    addressbooks
+                let ucfg = Ucfg {
+                    base: Base::request(message, message_id),
+                    addressbooks: addressbooks.to_string()
                 };
 
                 serde_json::to_string(&ucfg).unwrap()
             },
 
             CommandKind::INFO => {
-                let info = Base::request("server-info", 1);
+                let info = Base::request(message, message_id);
 
                 serde_json::to_string(&info).unwrap()
             },
 
             CommandKind::FRCG => {
-                let frcg = Base::request("rcg-get-params", 121);
+                let frcg = Base::request(message, message_id);
+
+                serde_json::to_string(&frcg).unwrap()
+            },
+
+            CommandKind::SetFaceRecognitionParams(params) => {
+                let frcg = RcgSetParams {
+                    base: Base::request(message, message_id),
+                    params
+                };
 
                 serde_json::to_string(&frcg).unwrap()
             },
 
+            CommandKind::ListUsers => {
+                let uadm = Base::request(message, message_id);
+
+                serde_json::to_string(&uadm).unwrap()
+            },
+
+            CommandKind::RemoveUser(user_token) => {
+                let uadm = RemoveUser {
+                    base: Base::request(message, message_id),
+                    user_token
+                };
+
+                serde_json::to_string(&uadm).unwrap()
+            },
+
             CommandKind::RemoveAllUsers(requester) => {
                 let fact = RemoveAllUsers {
-                    base: Base::request("remove-all-users", 1),
+                    base: Base::request(message, message_id),
                     requester
                 };
 
@@ -111,14 +228,44 @@ impl Command {
                 let fact = ActivateUser {
                     email,
                     description: String::from("viper-client"),
-                    base: Base::request("activate-user", 1)
+                    base: Base::request(message, message_id)
                 };
 
                 serde_json::to_string(&fact).unwrap()
+            },
+
+            CommandKind::PushInfo(token) => {
+                let push = PushInfo {
+                    base: Base::request(message, message_id),
+                    token
+                };
+
+                serde_json::to_string(&push).unwrap()
             }
         };
 
-        Command::make(&json.as_bytes(), control)
+        Command::make(json.as_bytes(), control)
+    }
+
+    // Fills in the `message-type` and `message-id` of a raw JSON request,
+    // which has to be an object for `message_of` to accept it.
+    pub fn json(mut request: Value,
+                message_id: u32,
+                control: &[u8]) -> Result<Vec<u8>, FrameError> {
+
+        request["message-type"] = json!("request");
+        request["message-id"] = json!(message_id);
+
+        Command::make(request.to_string().as_bytes(), control)
+    }
+
+    pub(crate) fn message_of(request: &Value) -> Result<String, ViperError> {
+        match request.get("message").and_then(Value::as_str) {
+            Some(message) => Ok(message.to_string()),
+            None => Err(ViperError::JSONError(serde::de::Error::custom(
+                "A request has to be an object with a \"message\""
+            )))
+        }
     }
 
     pub fn buffer_length(b2: u8, b3: u8) -> usize {
@@ -135,46 +282,42 @@ impl Command {
             Some(bytes) => {
                 let len = (bytes.len() + 1) as u8;
                 let start = [0, 0, len, 0, 0, 0];
-                [&start[..], &bytes[..], &[0]].concat()
+                [&start[..], bytes, &[0]].concat()
             },
             None => vec![0]
         };
 
         let total = [
             &OPEN,
-            &com_b[..],
-            &control[..],
+            com_b,
+            control,
             &tail[..]
         ].concat();
 
-        let header = Command::header(&total);
-
-        [&header, &total[..]].concat()
+        Command::template(&total, &[0, 0])
     }
 
     pub fn close(channel: &[u8]) -> Vec<u8> {
         let total = [
             &CLOSE,
-            &channel[..],
+            channel,
         ].concat();
 
-        let header = Command::header(&total);
-
-        [&header, &total[..]].concat()
+        Command::template(&total, &[0, 0])
     }
 
-    pub fn make(b_com: &[u8], control: &[u8]) -> Vec<u8> {
-        let mut header = Command::header(b_com);
-        header[4] = control[0];
-        header[5] = control[1];
-
-        [&header, &b_com[..]].concat()
+    // Anything that holds user input, like a token or a raw JSON request,
+    // can turn out to be too large for a single frame.
+    pub fn make(b_com: &[u8], control: &[u8]) -> Result<Vec<u8>, FrameError> {
+        Frame::new([control[0], control[1]], b_com.to_vec()).encode()
     }
 
-    fn header(total: &[u8]) -> [u8; 8] {
-        let len = total.len().to_le_bytes();
+    // Only for the requests that are built out of the fixed-size
+    // templates in this crate, which are a couple of dozen bytes long.
+    pub(crate) fn template(b_com: &[u8], control: &[u8]) -> Vec<u8> {
+        let frame = Frame::new([control[0], control[1]], b_com.to_vec());
 
-        [0x00, 0x06, len[0], len[1], 0x00, 0x00, 0x00, 0x00]
+        [&frame.header(), b_com].concat()
     }
 }
 
@@ -186,8 +329,69 @@ mod tests {
     fn test_for_kind() {
         let control = [1, 2];
 
-        let channel = Command::for_kind(CommandKind::UAUT("token".to_string()), &control);
+        let channel = Command::for_kind(CommandKind::UAUT("token".to_string()), 1, &control).unwrap();
         assert_eq!(channel.len(), 89);
+
+        let channel = Command::for_kind(CommandKind::INFO, 121, &control).unwrap();
+        assert_eq!(
+            &channel[8..],
+            br#"{"message":"server-info","message-type":"request","message-id":121}"#
+        );
+    }
+
+    #[test]
+    fn test_address_books() {
+        let channel = Command::for_kind(CommandKind::UCFG(AddressBooks::All), 2, &[1, 2]).unwrap();
+        let json: Value = serde_json::from_slice(&channel[8..]).unwrap();
+        assert_eq!(json["addressbooks"], "all");
+
+        assert_eq!(AddressBooks::None.to_string(), "none");
+        assert_eq!(AddressBooks::Custom(String::from("apt")).to_string(), "apt");
+    }
+
+    #[test]
+    fn test_push_info() {
+        let token = PushToken {
+            apt_address: String::from("SB000006"),
+            apt_subaddress: 2,
+            bundle_id: String::from("com.comelitgroup.friendhome"),
+            os_type: String::from("ios"),
+            profile_id: String::from("3"),
+            device_token: String::from("abcdef")
+        };
+
+        let channel = Command::for_kind(CommandKind::PushInfo(token), 4, &[1, 2]).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&channel[8..]).unwrap();
+
+        assert_eq!(json["message"], "push-info");
+        assert_eq!(json["message-id"], 4);
+        assert_eq!(json["apt-subaddress"], 2);
+        assert_eq!(json["bundle-id"], "com.comelitgroup.friendhome");
+        assert_eq!(json["device-token"], "abcdef");
+    }
+
+    #[test]
+    fn test_json() {
+        let request = json!({ "message": "server-info", "message-id": 0 });
+        assert_eq!(Command::message_of(&request).unwrap(), "server-info");
+
+        let channel = Command::json(request, 7, &[1, 2]).unwrap();
+        let json: Value = serde_json::from_slice(&channel[8..]).unwrap();
+        assert_eq!(json["message-type"], "request");
+        assert_eq!(json["message-id"], 7);
+
+        assert!(Command::message_of(&json!("server-info")).is_err());
+        assert!(Command::message_of(&json!({ "message": 1 })).is_err());
+    }
+
+    #[test]
+    fn test_json_too_large() {
+        let request = json!({ "message": "server-info", "padding": "A".repeat(70_000) });
+
+        assert!(matches!(
+            Command::json(request, 7, &[1, 2]),
+            Err(FrameError::TooLarge(_))
+        ));
     }
 
     #[test]
@@ -204,7 +408,7 @@ mod tests {
         for (byte_length, b2, b3) in list {
             let mut s = String::from("A");
             s = s.repeat(byte_length);
-            let b = Command::make(s.as_bytes(), &control);
+            let b = Command::make(s.as_bytes(), &control).unwrap();
             assert_eq!(b[2], b2);
             assert_eq!(b[3], b3);
         }
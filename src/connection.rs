@@ -0,0 +1,60 @@
+use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use crate::channel::{Channel, ChannelHandle, ChannelKind};
+use crate::channel_allocator::ChannelAllocator;
+use crate::stream_wrapper::StreamWrapper;
+use crate::ViperError;
+
+// Everything that's shared between a client and the background tasks
+// that run on the same connection, like the keepalive.
+pub struct Connection {
+    pub stream: StreamWrapper,
+    channels: Mutex<ChannelAllocator>,
+    message_id: AtomicU32,
+    alive: AtomicBool
+}
+
+impl Connection {
+    pub fn new(stream: StreamWrapper) -> Connection {
+        Connection {
+            stream,
+            channels: Mutex::new(ChannelAllocator::new()),
+            message_id: AtomicU32::new(1),
+            alive: AtomicBool::new(true)
+        }
+    }
+
+    // Opens a channel, which is closed again once the handle is dropped
+    pub fn channel(&self, kind: ChannelKind) -> Result<ChannelHandle<'_>, ViperError> {
+        let mut handle = self.handle(kind)?;
+        handle.open()?;
+
+        Ok(handle)
+    }
+
+    // Allocates a channel without opening it, for channels that need
+    // extra data when they're opened.
+    pub fn handle(&self, kind: ChannelKind) -> Result<ChannelHandle<'_>, ViperError> {
+        let control = self.allocator().allocate()?;
+
+        Ok(ChannelHandle::new(self, Channel::new(&control, kind)))
+    }
+
+    pub fn allocator(&self) -> MutexGuard<'_, ChannelAllocator> {
+        self.channels.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn next_message_id(&self) -> u32 {
+        self.message_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    // Dead once the keepalive gives up, or once the reader thread
+    // stops because the device hung up.
+    pub fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::SeqCst) && self.stream.is_open()
+    }
+
+    pub fn mark_dead(&self) {
+        self.alive.store(false, Ordering::SeqCst);
+    }
+}
@@ -1,4 +1,6 @@
+use crate::channel::ChannelKind;
 use crate::command::Command;
+use crate::ctpp_packet::{CtppPacket, CtppPacketKind};
 use crate::helper::Helper;
 
 // Every replaceable character in this template
@@ -17,12 +19,17 @@ const OPEN_DOOR_TEMPLATE: [u8; 48] = [
     0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // Actuator 1
     0x00,
     0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // Actuator 2
-    0x00, 0x00, 0x01, 0x20,
+    0x00, 0x00, 0xFF, 0x20,  // The module of actuator 1
     0xFF, 0xFF, 0xFF, 0xFF,  // 4 other random bytes
     0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // Actuator 1
     0x00, 0x49, 0x49
 ];
 
+const CALL_TEMPLATE: [u8; 16] = [
+    0xc0, 0x18, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x08,
+    0xFF, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+];
+
 const ACK_TEMPLATE: [u8; 8] = [
     0xFF, 0x18, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00
 ];
@@ -33,32 +40,80 @@ const TAIL_TEMPLATE: [u8; 24] = [
     0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00
 ];
 
+// The Q1 till Q4 bytes of a CTPP packet. Q1 and Q2 identify the
+// exchange, the device answers with 0x80 added to Q1. Q3 is the last
+// sequence number that was seen from the other side, Q4 the sender's
+// own. Acknowledgements bump the sender's own number, both sides keep
+// their own count and let it wrap around.
+#[derive(Debug, Clone, PartialEq)]
+struct Sequence {
+    id: [u8; 2],
+    remote: u8,
+    local: u8
+}
+
+impl Sequence {
+    fn random() -> Sequence {
+        let bytes = Helper::gen_ran(4);
+
+        Sequence {
+            id: [bytes[0], bytes[1]],
+            remote: bytes[2],
+            local: bytes[3]
+        }
+    }
+
+    fn bytes(&self) -> [u8; 4] {
+        [self.id[0], self.id[1], self.remote, self.local]
+    }
+
+    fn ack(&mut self) -> [u8; 4] {
+        self.local = self.local.wrapping_add(1);
+        self.bytes()
+    }
+
+    // The device acknowledges what it received from us by sending our
+    // own number back. Its own number is bumped by an acknowledgement,
+    // so it's never more than one ahead of the last one that was seen.
+    fn confirms(&self, sequence: &[u8; 4]) -> bool {
+        self.belongs(sequence) &&
+        sequence[2] == self.local &&
+        (sequence[3] == self.remote || sequence[3] == self.remote.wrapping_add(1))
+    }
+
+    fn receive(&mut self, sequence: &[u8; 4]) {
+        if self.belongs(sequence) {
+            self.remote = sequence[3];
+        }
+    }
+
+    fn belongs(&self, sequence: &[u8; 4]) -> bool {
+        sequence[0] == self.id[0].wrapping_add(0x80) && sequence[1] == self.id[1]
+    }
+}
+
 #[derive(Debug)]
 pub struct CTPPChannel {
-    control: [u8; 2],
-    bitmask: Vec<u8>
+    pub control: [u8; 2],
+    sequence: Sequence
 }
 
 impl CTPPChannel {
     pub fn new(control: &[u8; 2]) -> CTPPChannel {
         CTPPChannel {
             control: *control,
-            bitmask: Helper::gen_ran(4)
+            sequence: Sequence::random()
         }
     }
 
     pub fn open(&self, sub: &String) -> Vec<u8> {
         Command::channel(
-            &String::from("CTPP"),
+            &ChannelKind::CTPP.to_string(),
             &self.control,
-            Some(&sub.as_bytes()),
+            Some(sub.as_bytes()),
         )
     }
 
-    pub fn close(&self) -> Vec<u8> {
-        Command::close(&self.control)
-    }
-
     // This is the initial call that's made right after
     // the CTPP channel is opened
     // You have to read replies (max times is 2) until a response
@@ -69,24 +124,31 @@ impl CTPPChannel {
             &TAIL_TEMPLATE[..]
         ].concat();
 
-        CTPPChannel::set_bytes(&mut req, &self.bitmask, 2);
+        CTPPChannel::set_bytes(&mut req, &self.sequence.bytes(), 2);
         CTPPChannel::set_bytes(&mut req, &Helper::gen_ran(2), 10);
-        CTPPChannel::set_bytes(&mut req, &a1.as_bytes(), 12);
-        CTPPChannel::set_bytes(&mut req, &a1.as_bytes(), 32);
-        CTPPChannel::set_bytes(&mut req, &a2.as_bytes(), 42);
+        CTPPChannel::set_bytes(&mut req, a1.as_bytes(), 12);
+        CTPPChannel::set_bytes(&mut req, a1.as_bytes(), 32);
+        CTPPChannel::set_bytes(&mut req, a2.as_bytes(), 42);
 
-        Command::make(&req, &self.control)
+        Command::template(&req, &self.control)
     }
 
     pub fn confirm_handshake(&self, r: &[u8]) -> bool {
-        r[0] == 0x60 && self.confirm(r)
+        self.confirmed_by(r, |packet| packet.kind == CtppPacketKind::Reply)
     }
 
     pub fn confirm(&self, r: &[u8]) -> bool {
-        self.bitmask[0] + 0x80 == r[2] &&
-        self.bitmask[1] == r[3] &&
-        self.bitmask[2] == r[5] - 1 &&
-        self.bitmask[3] == r[4]
+        self.confirmed_by(r, |_| true)
+    }
+
+    // Every reply that's read from the channel has to pass through here,
+    // so the acknowledgements that follow carry the device's number.
+    pub fn receive(&mut self, r: &[u8]) {
+        let packets = CtppPacket::decode(r).unwrap_or_default();
+
+        if let Some(packet) = packets.last() {
+            self.sequence.receive(&packet.sequence);
+        }
     }
 
     pub fn ack(&mut self,
@@ -94,47 +156,102 @@ impl CTPPChannel {
                a1: &String,
                a2: &String) -> Vec<u8> {
 
+        // The 0x20 repeats the 0x00 that comes right before it
+        let sequence = match prefix {
+            0x00 => self.sequence.ack(),
+            _ => self.sequence.bytes()
+        };
+
+        self.ack_with(prefix, sequence, a1, a2)
+    }
+
+    // What the device expects after it confirmed the handshake, before
+    // anything else is sent on the channel.
+    pub fn handshake_acks(&mut self, a1: &String, a2: &String) -> Vec<Vec<u8>> {
+        vec![
+            self.ack(0x00, a1, a2),
+            self.ack(0x20, a1, a2)
+        ]
+    }
+
+    // Acknowledges a packet in an exchange that the device started, see
+    // `call_action`.
+    pub fn call_ack(&self, sequence: [u8; 4], a1: &String, a2: &String) -> Vec<u8> {
+        self.ack_with(0x00, sequence, a1, a2)
+    }
+
+    fn ack_with(&self,
+                prefix: u8,
+                sequence: [u8; 4],
+                a1: &String,
+                a2: &String) -> Vec<u8> {
+
         let mut req = [
             &ACK_TEMPLATE[..],
             &TAIL_TEMPLATE[..]
         ].concat();
 
-        if prefix == 0x00 {
-            self.tick_mask();
-        }
-
         CTPPChannel::set_bytes(&mut req, &[prefix], 0);
-        CTPPChannel::set_bytes(&mut req, &self.bitmask, 2);
-        CTPPChannel::set_bytes(&mut req, &a1.as_bytes(), 12);
-        CTPPChannel::set_bytes(&mut req, &a2.as_bytes(), 22);
+        CTPPChannel::set_bytes(&mut req, &sequence, 2);
+        CTPPChannel::set_bytes(&mut req, a1.as_bytes(), 12);
+        CTPPChannel::set_bytes(&mut req, a2.as_bytes(), 22);
 
-        return Command::make(&req, &self.control)
+        Command::template(&req, &self.control)
     }
 
     pub fn link_actuators(&mut self,
                           a1: &String,
-                          a2: &String) -> Vec<u8> {
+                          a2: &String,
+                          module_index: u8) -> Vec<u8> {
 
         let mut req = [
             &OPEN_DOOR_TEMPLATE[..],
             &TAIL_TEMPLATE[..]
         ].concat();
 
-        self.bitmask = Helper::gen_ran(4);
+        // The link starts an exchange of its own
+        self.sequence = Sequence::random();
 
-        CTPPChannel::set_bytes(&mut req, &self.bitmask, 2);
-        CTPPChannel::set_bytes(&mut req, &a1.as_bytes(), 10);
-        CTPPChannel::set_bytes(&mut req, &a2.as_bytes(), 20);
+        CTPPChannel::set_bytes(&mut req, &self.sequence.bytes(), 2);
+        CTPPChannel::set_bytes(&mut req, a1.as_bytes(), 10);
+        CTPPChannel::set_bytes(&mut req, a2.as_bytes(), 20);
+        CTPPChannel::set_bytes(&mut req, &[module_index], 30);
         CTPPChannel::set_bytes(&mut req, &Helper::gen_ran(4), 32);
-        CTPPChannel::set_bytes(&mut req, &a1.as_bytes(), 36);
-        CTPPChannel::set_bytes(&mut req, &a1.as_bytes(), 52);
-        CTPPChannel::set_bytes(&mut req, &a2.as_bytes(), 62);
+        CTPPChannel::set_bytes(&mut req, a1.as_bytes(), 36);
+        CTPPChannel::set_bytes(&mut req, a1.as_bytes(), 52);
+        CTPPChannel::set_bytes(&mut req, a2.as_bytes(), 62);
 
-        return Command::make(&req, &self.control)
+        Command::template(&req, &self.control)
     }
 
-    fn tick_mask(&mut self) {
-        self.bitmask[3] += 1;
+    // Answers in an exchange that the device started, like a ring, so
+    // it's sent with the sequence of that exchange instead of our own.
+    pub fn call_action(&self,
+                       sequence: [u8; 4],
+                       action: [u8; 2],
+                       a1: &String,
+                       a2: &String) -> Vec<u8> {
+
+        let mut req = [
+            &CALL_TEMPLATE[..],
+            &TAIL_TEMPLATE[..]
+        ].concat();
+
+        CTPPChannel::set_bytes(&mut req, &sequence, 2);
+        CTPPChannel::set_bytes(&mut req, &action, 8);
+        CTPPChannel::set_bytes(&mut req, a1.as_bytes(), 20);
+        CTPPChannel::set_bytes(&mut req, a2.as_bytes(), 30);
+
+        Command::template(&req, &self.control)
+    }
+
+    fn confirmed_by<F>(&self, r: &[u8], kind: F) -> bool
+        where F: Fn(&CtppPacket) -> bool {
+
+        CtppPacket::decode(r)
+            .unwrap_or_default()
+            .iter()
+            .any(|packet| kind(packet) && self.sequence.confirms(&packet.sequence))
     }
 
     fn set_bytes(template: &mut [u8], bytes: &[u8], offset: usize) {
@@ -182,14 +299,14 @@ mod tests {
     #[test]
     fn test_ack() {
         let mut ctpp = CTPPChannel::new(&[1, 2]);
-        let mask = ctpp.bitmask[3];
+        let local = ctpp.sequence.local;
         let conn = ctpp.ack(
             0x00,
             &String::from("SB0000062"),
             &String::from("SB000006")
         );
 
-        assert_eq!(conn[13], mask + 1);
+        assert_eq!(conn[13], local.wrapping_add(1));
         assert_eq!(&conn[2], &32);
         assert_eq!(&conn[8..10], &[0, 24]);
         assert_eq!(str::from_utf8(&conn[20..29]).unwrap(), "SB0000062");
@@ -205,7 +322,7 @@ mod tests {
         assert_eq!(&conn_2[8..10], &[32, 24]);
         assert_eq!(str::from_utf8(&conn_2[20..29]).unwrap(), "SB0000062");
         assert_eq!(str::from_utf8(&conn_2[30..38]).unwrap(), "SB000006");
-        // This is to test that the bitmask doesn't swap between
+        // This is to test that the sequence doesn't change between
         // 0x00 and 0x20 calls
         assert_eq!(&conn_2[10..14], &conn[10..14]);
     }
@@ -214,29 +331,80 @@ mod tests {
     fn test_confirm_handshake() {
         let ctpp = CTPPChannel {
             control: [1, 2],
-            bitmask: vec![0x42, 0x70, 0x2f, 0x50]
+            sequence: Sequence { id: [0x42, 0x70], remote: 0x2f, local: 0x50 }
         };
 
-        assert_eq!(
-            ctpp.confirm_handshake(
-                &[0x00, 0x18, 0xc2, 0x70, 0x50, 0x30]
-            ),
-            false
+        assert!(
+            !ctpp.confirm_handshake(
+                &[0x00, 0x18, 0xc2, 0x70, 0x50, 0x30, 0x00, 0x00]
+            )
         );
 
-        assert_eq!(
+        assert!(
             ctpp.confirm_handshake(
-                &[0x60, 0x18, 0xc2, 0x70, 0x50, 0x30]
-            ),
-            true
+                &[0x60, 0x18, 0xc2, 0x70, 0x50, 0x30, 0x00, 0x00]
+            )
         );
 
-        assert_eq!(
+        assert!(
+            !ctpp.confirm_handshake(
+                &[0x60, 0x18, 0xc1, 0x70, 0x50, 0x30, 0x00, 0x00]
+            )
+        );
+
+        // The reply comes after an acknowledgement in the same frame
+        assert!(
             ctpp.confirm_handshake(
-                &[0x60, 0x18, 0xc1, 0x70, 0x50, 0x30]
-            ),
-            false
+                &[
+                    0x00, 0x18, 0xc2, 0x70, 0x50, 0x30, 0x00, 0x00,
+                    0x60, 0x18, 0xc2, 0x70, 0x50, 0x30, 0x00, 0x00
+                ]
+            )
         );
+
+        // Too short to hold a packet
+        assert!(!ctpp.confirm(&[]));
+    }
+
+    #[test]
+    fn test_sequence_follows_trace() {
+        // The first 0x40 example in docs/README.md
+        let mut sequence = Sequence { id: [0x1f, 0x6b], remote: 0xfe, local: 0xd0 };
+        assert_eq!(sequence.bytes(), [0x1f, 0x6b, 0xfe, 0xd0]);
+
+        let replies = [
+            ([0x9f, 0x6b, 0xd0, 0xff], Some([0x1f, 0x6b, 0xff, 0xd1])),
+            ([0x9f, 0x6b, 0xd1, 0xff], Some([0x1f, 0x6b, 0xff, 0xd2])),
+            ([0x9f, 0x6b, 0xd2, 0xff], Some([0x1f, 0x6b, 0xff, 0xd3])),
+            ([0x9f, 0x6b, 0xd2, 0x00], None)
+        ];
+
+        assert!(sequence.confirms(&replies[0].0));
+
+        for (reply, ack) in replies {
+            sequence.receive(&reply);
+
+            if let Some(ack) = ack {
+                assert_eq!(sequence.ack(), ack);
+            }
+        }
+
+        // The device's number wrapped around from 0xff
+        assert_eq!(sequence.bytes(), [0x1f, 0x6b, 0x00, 0xd3]);
+        assert!(sequence.confirms(&[0x9f, 0x6b, 0xd3, 0x01]));
+    }
+
+    #[test]
+    fn test_sequence_wraps_around() {
+        let mut sequence = Sequence { id: [0x7f, 0x01], remote: 0xff, local: 0xff };
+
+        assert_eq!(sequence.ack(), [0x7f, 0x01, 0xff, 0x00]);
+        assert!(sequence.confirms(&[0xff, 0x01, 0x00, 0x00]));
+        assert!(!sequence.confirms(&[0xff, 0x01, 0x00, 0x01]));
+
+        // Packets of another exchange are left alone
+        sequence.receive(&[0x00, 0x00, 0x00, 0x42]);
+        assert_eq!(sequence.remote, 0xff);
     }
 
     #[test]
@@ -244,7 +412,8 @@ mod tests {
         let mut ctpp = CTPPChannel::new(&[1, 2]);
         let conn = ctpp.link_actuators(
             &String::from("SB0000062"),
-            &String::from("SB000006")
+            &String::from("SB000006"),
+            0x01
         );
 
         assert_eq!(&conn[2], &72);
@@ -252,9 +421,30 @@ mod tests {
         assert_eq!(str::from_utf8(&conn[18..27]).unwrap(), "SB0000062");
         assert_eq!(&conn[27], &0);
         assert_eq!(str::from_utf8(&conn[28..36]).unwrap(), "SB000006");
+        assert_eq!(&conn[38..40], &[0x01, 0x20]);
         assert_eq!(str::from_utf8(&conn[44..53]).unwrap(), "SB0000062");
         assert_eq!(str::from_utf8(&conn[60..69]).unwrap(), "SB0000062");
         assert_eq!(&conn[69], &0);
         assert_eq!(str::from_utf8(&conn[70..78]).unwrap(), "SB000006");
     }
+
+    #[test]
+    fn test_call_action() {
+        let ctpp = CTPPChannel::new(&[1, 2]);
+        let call = ctpp.call_action(
+            [0x12, 0x34, 0x02, 0x01],
+            [0x00, 0x21],
+            &String::from("SB0000062"),
+            &String::from("SB100001")
+        );
+
+        let packets = CtppPacket::decode(&call[8..]).unwrap();
+        assert_eq!(packets[0].kind, CtppPacketKind::Command);
+        assert_eq!(packets[0].sequence, [0x12, 0x34, 0x02, 0x01]);
+        assert_eq!(&packets[0].body[0..2], &[0x00, 0x21]);
+        assert_eq!(
+            packets[0].actuators(),
+            Some((String::from("SB0000062"), String::from("SB100001")))
+        );
+    }
 }
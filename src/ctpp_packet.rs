@@ -0,0 +1,217 @@
+use std::{error, fmt};
+
+// Type, footer length, four sequence bytes and the length of the body
+const HEADER_LENGTH: usize = 8;
+
+// Every footer starts with this, see docs/README.md
+const FOOTER_MARKER: [u8; 4] = [0xff, 0xff, 0xff, 0xff];
+
+/// The first byte of a CTPP packet, see the table in docs/README.md
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CtppPacketKind {
+    // 0x00, acknowledges the packet that came in before it
+    Ack,
+    // 0x20, the second acknowledgement after a handshake
+    Confirm,
+    // 0x40, seen while setting up the UDPM and RTPC channels
+    Data,
+    // 0x60, the reply to a handshake
+    Reply,
+    // 0xc0, the handshake and linking actuators
+    Command,
+    Unknown(u8)
+}
+
+impl From<u8> for CtppPacketKind {
+    fn from(byte: u8) -> Self {
+        match byte {
+            0x00 => CtppPacketKind::Ack,
+            0x20 => CtppPacketKind::Confirm,
+            0x40 => CtppPacketKind::Data,
+            0x60 => CtppPacketKind::Reply,
+            0xc0 => CtppPacketKind::Command,
+            other => CtppPacketKind::Unknown(other)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CtppPacketError {
+    // The bytes that are left over are too short to hold a packet
+    Truncated(Vec<u8>),
+    // The body is said to be longer than the bytes that are left over
+    InvalidLength(usize)
+}
+
+impl fmt::Display for CtppPacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CtppPacketError::Truncated(bytes) =>
+                write!(f, "Truncated CTPP packet {:02x?}", bytes),
+            CtppPacketError::InvalidLength(length) =>
+                write!(f, "CTPP packet body of {} bytes doesn't fit", length),
+        }
+    }
+}
+
+impl error::Error for CtppPacketError {}
+
+/// A single packet on the CTPP channel, which looks like:
+///
+/// ```text
+/// A1 A2 Q1 Q2 Q3 Q4 L1 L2 <-- Type, footer length, sequence, body length
+/// [[ BODY ]]              <-- L1 L2 bytes in big endian, padded to 4 bytes
+/// [[ FOOTER ]]            <-- A2 bytes, when it starts with ff ff ff ff
+/// ```
+///
+/// The device is free to put more than one packet in a frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CtppPacket {
+    pub kind: CtppPacketKind,
+    pub sequence: [u8; 4],
+    pub body: Vec<u8>,
+    pub footer: Option<Vec<u8>>
+}
+
+impl CtppPacket {
+    /// Decodes every packet in the payload of a frame
+    pub fn decode(bytes: &[u8]) -> Result<Vec<CtppPacket>, CtppPacketError> {
+        let mut packets = vec![];
+        let mut rest = bytes;
+
+        while !rest.is_empty() {
+            let (packet, length) = Self::decode_one(rest)?;
+            packets.push(packet);
+            rest = &rest[length..];
+        }
+
+        Ok(packets)
+    }
+
+    /// The two actuators the footer links together, like the apartment
+    /// address with its sub-address and the apartment address itself.
+    pub fn actuators(&self) -> Option<(String, String)> {
+        let footer = self.footer.as_ref()?;
+        let ids = footer.get(FOOTER_MARKER.len()..)?;
+
+        let mut parts = ids.split(|b| *b == 0x00).map(Self::actuator);
+
+        Some((parts.next()?, parts.next()?))
+    }
+
+    // A shorter id leaves the rest of its 0xff placeholders in place
+    fn actuator(bytes: &[u8]) -> String {
+        let end = bytes.iter().position(|b| *b == 0xff).unwrap_or(bytes.len());
+
+        String::from_utf8_lossy(&bytes[..end]).to_string()
+    }
+
+    // Returns the packet and the amount of bytes it took up
+    fn decode_one(bytes: &[u8]) -> Result<(CtppPacket, usize), CtppPacketError> {
+        // An acknowledgement ends right after the sequence bytes
+        if bytes.len() < HEADER_LENGTH {
+            return Err(CtppPacketError::Truncated(bytes.to_vec()))
+        }
+
+        let body_length = u16::from_be_bytes([bytes[6], bytes[7]]) as usize;
+        let body_end = HEADER_LENGTH + body_length;
+        let padded_end = HEADER_LENGTH + body_length.next_multiple_of(4);
+
+        if body_end > bytes.len() {
+            return Err(CtppPacketError::InvalidLength(body_length))
+        }
+
+        let mut end = padded_end.min(bytes.len());
+        let footer_length = bytes[1] as usize;
+        let footer = match bytes.get(end..end + footer_length) {
+            Some(footer) if footer.starts_with(&FOOTER_MARKER) => {
+                end += footer_length;
+                Some(footer.to_vec())
+            },
+            _ => None
+        };
+
+        let packet = CtppPacket {
+            kind: CtppPacketKind::from(bytes[0]),
+            sequence: [bytes[2], bytes[3], bytes[4], bytes[5]],
+            body: bytes[HEADER_LENGTH..body_end].to_vec(),
+            footer
+        };
+
+        Ok((packet, end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ctpp_channel::CTPPChannel;
+
+    #[test]
+    fn test_decode_handshake() {
+        let ctpp = CTPPChannel::new(&[1, 2]);
+        let handshake = ctpp.connect_hs(
+            &String::from("SB0000062"),
+            &String::from("SB000006")
+        );
+
+        let packets = CtppPacket::decode(&handshake[8..]).unwrap();
+        assert_eq!(packets.len(), 1);
+
+        let packet = &packets[0];
+        assert_eq!(packet.kind, CtppPacketKind::Command);
+        assert_eq!(&packet.sequence[..], &handshake[10..14]);
+        assert_eq!(packet.body.len(), 0x11);
+        assert_eq!(&packet.body[0..2], &[0x00, 0x40]);
+        assert_eq!(
+            packet.actuators(),
+            Some((String::from("SB0000062"), String::from("SB000006")))
+        );
+    }
+
+    #[test]
+    fn test_decode_data() {
+        // From the first 0x40 example in docs/README.md
+        let bytes = [
+            0x40, 0x18, 0x1f, 0x6b, 0xff, 0xd2, 0x00, 0x0a,
+            0x00, 0x11, 0x18, 0x02, 0x00, 0x00, 0x00, 0x00,
+            0x0a, 0x7b, 0x00, 0x00
+        ];
+
+        let packets = CtppPacket::decode(&bytes).unwrap();
+        assert_eq!(packets[0].kind, CtppPacketKind::Data);
+        assert_eq!(packets[0].sequence, [0x1f, 0x6b, 0xff, 0xd2]);
+        assert_eq!(packets[0].body.len(), 10);
+        assert_eq!(&packets[0].body[8..], &[0x0a, 0x7b]);
+        assert_eq!(packets[0].footer, None);
+    }
+
+    #[test]
+    fn test_decode_multiple_packets() {
+        let bytes = [
+            0x00, 0x18, 0x9f, 0x6b, 0xd0, 0xff, 0x00, 0x00,
+            0x40, 0x18, 0x9f, 0x6b, 0xd0, 0xff, 0x00, 0x08,
+            0x00, 0x03, 0x50, 0x03, 0x3b, 0x00, 0x00, 0x00
+        ];
+
+        let packets = CtppPacket::decode(&bytes).unwrap();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].kind, CtppPacketKind::Ack);
+        assert!(packets[0].body.is_empty());
+        assert_eq!(packets[1].kind, CtppPacketKind::Data);
+        assert_eq!(packets[1].body, vec![0x00, 0x03, 0x50, 0x03, 0x3b, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(
+            CtppPacket::decode(&[0x00, 0x18, 0x9f]),
+            Err(CtppPacketError::Truncated(vec![0x00, 0x18, 0x9f]))
+        );
+
+        assert_eq!(
+            CtppPacket::decode(&[0x40, 0x18, 0x9f, 0x6b, 0xd0, 0xff, 0x00, 0x08, 0x00]),
+            Err(CtppPacketError::InvalidLength(8))
+        );
+    }
+}
@@ -0,0 +1,313 @@
+use std::{fmt, fmt::Display};
+use crate::command_response::{Opendoor, UserParametersResponse, VipResponse};
+use crate::ctpp_channel::CTPPChannel;
+
+// The amount of replies the device can send in a step, before it's clear
+// that it's not going to send the one the step is waiting for.
+const MAX_REPLIES: usize = 2;
+
+// Doors are linked through the first module of their address, other
+// actuators list the module they're on in the configuration.
+pub(crate) const DOOR_MODULE: u8 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DoorOpenStep {
+    // Waiting for the device to answer the handshake with a 0x60
+    Handshake,
+    // Waiting for the device to acknowledge the linked actuators
+    LinkActuators
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DoorOpenError {
+    // The configuration doesn't list a door to open
+    NoDoor,
+    // There's no door by the requested name or id, followed by the
+    // name and id of every door there is.
+    UnknownDoor(String, Vec<(String, String)>),
+    // The device didn't reply in time during the step
+    Timeout(DoorOpenStep),
+    // The device did reply, but never with a confirmation. Holds the
+    // last reply that was received.
+    Unconfirmed(DoorOpenStep, Vec<u8>)
+}
+
+impl Display for DoorOpenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DoorOpenError::NoDoor =>
+                write!(f, "There's no door in the configuration"),
+            DoorOpenError::UnknownDoor(requested, doors) => {
+                let doors: Vec<String> = doors
+                    .iter()
+                    .map(|(name, id)| format!("{} ({})", name, id))
+                    .collect();
+
+                write!(f, "There's no door {}, pick one of: {}", requested, doors.join(", "))
+            },
+            DoorOpenError::Timeout(step) =>
+                write!(f, "The device didn't reply during {:?}", step),
+            DoorOpenError::Unconfirmed(step, reply) =>
+                write!(f, "The device didn't confirm {:?}, last reply: {:02x?}", step, reply),
+        }
+    }
+}
+
+pub(crate) enum DoorLookup<'a> {
+    Name(&'a str),
+    Id(&'a str)
+}
+
+impl DoorLookup<'_> {
+    // Both the doors and the door actions of the configuration can
+    // be opened, and are looked up in that order.
+    pub fn actuator_id(&self, params: &UserParametersResponse) -> Result<String, DoorOpenError> {
+        let door = params.opendoor_address_book
+            .iter()
+            .find(|door| self.matches(&door.id, &door.name))
+            .map(Opendoor::actuator_id);
+
+        let action = || params.opendoor_actions
+            .iter()
+            .find(|action| self.matches(&action.id, &action.action))
+            .map(|action| action.actuator_id());
+
+        door.or_else(action).ok_or_else(|| {
+            let doors = params.opendoor_address_book
+                .iter()
+                .map(|door| (door.name.clone(), door.id.clone()))
+                .chain(
+                    params.opendoor_actions
+                        .iter()
+                        .map(|action| (action.action.clone(), action.id.clone()))
+                )
+                .collect();
+
+            DoorOpenError::UnknownDoor(self.requested().to_string(), doors)
+        })
+    }
+
+    fn matches(&self, id: &str, name: &str) -> bool {
+        match self {
+            DoorLookup::Name(requested) => *requested == name,
+            DoorLookup::Id(requested) => *requested == id
+        }
+    }
+
+    fn requested(&self) -> &str {
+        match self {
+            DoorLookup::Name(requested) | DoorLookup::Id(requested) => requested
+        }
+    }
+}
+
+enum State {
+    Handshake,
+    LinkActuators,
+    Opened
+}
+
+// The CTPP side of opening a door, without any of the IO. The caller
+// writes whatever the state machine hands back and feeds it every reply
+// that's read from the CTPP channel, until the door is open.
+pub(crate) struct DoorOpening {
+    ctpp_channel: CTPPChannel,
+    addr: String,
+    sub: String,
+    act: String,
+    module_index: u8,
+    state: State,
+    replies: usize
+}
+
+impl DoorOpening {
+    pub fn new(ctpp_channel: CTPPChannel,
+               vip: &VipResponse,
+               actuator_id: &str,
+               module_index: u8) -> DoorOpening {
+
+        let addr = vip.apt_address.to_string();
+
+        DoorOpening {
+            ctpp_channel,
+            sub: format!("{}{}", addr, vip.apt_subaddress),
+            addr,
+            act: actuator_id.to_string(),
+            module_index,
+            state: State::Handshake,
+            replies: 0
+        }
+    }
+
+    pub fn first_door(vip: &VipResponse) -> Result<&Opendoor, DoorOpenError> {
+        vip.user_parameters
+            .opendoor_address_book
+            .first()
+            .ok_or(DoorOpenError::NoDoor)
+    }
+
+    // The CTPP channel is opened with the apartment address
+    pub fn open(&self) -> Vec<u8> {
+        self.ctpp_channel.open(&self.sub)
+    }
+
+    pub fn handshake(&self) -> Vec<u8> {
+        self.ctpp_channel.connect_hs(&self.sub, &self.addr)
+    }
+
+    pub fn is_opened(&self) -> bool {
+        matches!(self.state, State::Opened)
+    }
+
+    // Returns the requests to write in response to the reply, which is
+    // nothing when the device has more to say first.
+    pub fn receive(&mut self, reply: &[u8]) -> Result<Vec<Vec<u8>>, DoorOpenError> {
+        // Taken over first, so the acknowledgements carry the number
+        // the device just sent.
+        self.ctpp_channel.receive(reply);
+        self.respond(reply)
+    }
+
+    fn respond(&mut self, reply: &[u8]) -> Result<Vec<Vec<u8>>, DoorOpenError> {
+        match self.state {
+            State::Handshake if self.ctpp_channel.confirm_handshake(reply) => {
+                self.next(State::LinkActuators);
+
+                let mut requests = self.ctpp_channel.handshake_acks(&self.sub, &self.addr);
+                requests.push(
+                    self.ctpp_channel.link_actuators(&self.act, &self.sub, self.module_index)
+                );
+
+                Ok(requests)
+            },
+            State::LinkActuators if self.ctpp_channel.confirm(reply) => {
+                self.next(State::Opened);
+                Ok(vec![])
+            },
+            State::Opened => Ok(vec![]),
+            _ => {
+                self.replies += 1;
+
+                if self.replies >= MAX_REPLIES {
+                    return Err(DoorOpenError::Unconfirmed(self.step(), reply.to_vec()))
+                }
+
+                Ok(vec![])
+            }
+        }
+    }
+
+    pub fn timed_out(&self) -> DoorOpenError {
+        DoorOpenError::Timeout(self.step())
+    }
+
+    fn step(&self) -> DoorOpenStep {
+        match self.state {
+            State::Handshake => DoorOpenStep::Handshake,
+            _ => DoorOpenStep::LinkActuators
+        }
+    }
+
+    fn next(&mut self, state: State) {
+        self.state = state;
+        self.replies = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_response::ConfigurationResponse;
+    use crate::test_helper::{confirm, CONFIGURATION_RESPONSE};
+
+    fn door_opening() -> DoorOpening {
+        let config: ConfigurationResponse = serde_json::from_str(CONFIGURATION_RESPONSE).unwrap();
+        let door = DoorOpening::first_door(&config.vip).unwrap();
+
+        DoorOpening::new(
+            CTPPChannel::new(&[1, 2]),
+            &config.vip,
+            &door.actuator_id(),
+            DOOR_MODULE
+        )
+    }
+
+    #[test]
+    fn test_opens_door() {
+        let mut door = door_opening();
+        let handshake = door.handshake();
+
+        // A plain acknowledgement comes in before the handshake reply
+        assert!(door.receive(&[0x00, 0x18, 0x00, 0x00, 0x00, 0x00]).unwrap().is_empty());
+
+        let requests = door.receive(&confirm(0x60, &handshake[8..])).unwrap();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(&requests[0][8..10], &[0x00, 0x18]);
+        assert_eq!(&requests[1][8..10], &[0x20, 0x18]);
+        assert_eq!(&requests[2][8..10], &[0xc0, 0x18]);
+        assert!(!door.is_opened());
+
+        door.receive(&confirm(0x00, &requests[2][8..])).unwrap();
+        assert!(door.is_opened());
+    }
+
+    #[test]
+    fn test_acks_carry_device_number() {
+        let mut door = door_opening();
+        let handshake = door.handshake();
+        let reply = confirm(0x60, &handshake[8..]);
+
+        let requests = door.receive(&reply).unwrap();
+        assert_eq!(requests[0][12], reply[5]);
+        assert_eq!(requests[1][12], reply[5]);
+    }
+
+    #[test]
+    fn test_unconfirmed() {
+        let mut door = door_opening();
+        let reply = [0x00, 0x18, 0x00, 0x00, 0x00, 0x00];
+
+        door.receive(&reply).unwrap();
+        assert_eq!(
+            door.receive(&reply),
+            Err(DoorOpenError::Unconfirmed(DoorOpenStep::Handshake, reply.to_vec()))
+        );
+        assert_eq!(door.timed_out(), DoorOpenError::Timeout(DoorOpenStep::Handshake));
+    }
+
+    #[test]
+    fn test_no_door() {
+        let mut config: ConfigurationResponse = serde_json::from_str(CONFIGURATION_RESPONSE).unwrap();
+        config.vip.user_parameters.opendoor_address_book.clear();
+
+        assert!(matches!(
+            DoorOpening::first_door(&config.vip),
+            Err(DoorOpenError::NoDoor)
+        ));
+    }
+
+    #[test]
+    fn test_door_lookup() {
+        let config: ConfigurationResponse = serde_json::from_str(CONFIGURATION_RESPONSE).unwrap();
+        let params = &config.vip.user_parameters;
+
+        assert_eq!(DoorLookup::Name("Back door").actuator_id(params).unwrap(), "SB1000022");
+        assert_eq!(DoorLookup::Id("3").actuator_id(params).unwrap(), "SB1000011");
+
+        let error = DoorLookup::Name("Garage").actuator_id(params).unwrap_err();
+        assert_eq!(
+            error,
+            DoorOpenError::UnknownDoor(
+                String::from("Garage"),
+                vec![
+                    (String::from("Front gate"), String::from("3")),
+                    (String::from("Back door"), String::from("4"))
+                ]
+            )
+        );
+        assert_eq!(
+            error.to_string(),
+            "There's no door Garage, pick one of: Front gate (3), Back door (4)"
+        );
+    }
+}
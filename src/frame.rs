@@ -0,0 +1,191 @@
+use std::{error, fmt, io};
+use std::io::prelude::*;
+use crate::command::Command;
+
+pub const HEADER_LENGTH: usize = 8;
+
+// The length of a frame is stored in two bytes, anything bigger
+// than this can't be described by the header.
+pub const MAX_PAYLOAD: usize = u16::MAX as usize;
+
+const MAGIC: [u8; 2] = [0x00, 0x06];
+
+/// A single message on the wire, which looks like:
+///
+/// ```text
+/// 00 06 L1 L2 C1 C2 00 00 <-- Header
+/// [[ PAYLOAD ]]           <-- L1 L2 bytes in little endian
+/// ```
+///
+/// `C1 C2` are the control bytes of the channel the frame belongs to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub channel: [u8; 2],
+    pub payload: Vec<u8>
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FrameError {
+    InvalidMagic([u8; 2]),
+    InvalidHeader([u8; HEADER_LENGTH]),
+    TooLarge(usize)
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::InvalidMagic(magic) =>
+                write!(f, "Frame starts with {:02x?} instead of {:02x?}", magic, MAGIC),
+            FrameError::InvalidHeader(header) =>
+                write!(f, "Malformed frame header {:02x?}", header),
+            FrameError::TooLarge(length) =>
+                write!(f, "Frame payload of {} bytes is too large", length),
+        }
+    }
+}
+
+impl error::Error for FrameError {}
+
+impl From<FrameError> for io::Error {
+    fn from(error: FrameError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
+impl Frame {
+    pub fn new(channel: [u8; 2], payload: Vec<u8>) -> Frame {
+        Frame { channel, payload }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, FrameError> {
+        if self.payload.len() > MAX_PAYLOAD {
+            return Err(FrameError::TooLarge(self.payload.len()))
+        }
+
+        Ok([&self.header(), &self.payload[..]].concat())
+    }
+
+    // The length is cut off at two bytes, `encode` checks it first
+    pub(crate) fn header(&self) -> [u8; HEADER_LENGTH] {
+        let len = (self.payload.len() as u16).to_le_bytes();
+
+        [
+            MAGIC[0], MAGIC[1],
+            len[0], len[1],
+            self.channel[0], self.channel[1],
+            0x00, 0x00
+        ]
+    }
+
+    /// Validates a header and returns the channel and the length of
+    /// the payload that follows it.
+    pub fn decode_header(head: &[u8; HEADER_LENGTH]) -> Result<([u8; 2], usize), FrameError> {
+        if head[0..2] != MAGIC {
+            return Err(FrameError::InvalidMagic([head[0], head[1]]))
+        }
+
+        if head[6..8] != [0x00, 0x00] {
+            return Err(FrameError::InvalidHeader(*head))
+        }
+
+        Ok((
+            [head[4], head[5]],
+            Command::buffer_length(head[2], head[3])
+        ))
+    }
+
+    /// Reads exactly one frame, blocking until both the header and the
+    /// complete payload have arrived.
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Frame> {
+        let mut head = [0; HEADER_LENGTH];
+        reader.read_exact(&mut head)?;
+
+        let (channel, length) = Frame::decode_header(&head)?;
+        let mut payload = vec![0; length];
+        reader.read_exact(&mut payload)?;
+
+        Ok(Frame { channel, payload })
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.encode()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Hands out the bytes it holds in chunks of at most `chunk` bytes,
+    // to mimic a socket returning short reads.
+    struct ChunkedReader {
+        bytes: Vec<u8>,
+        chunk: usize
+    }
+
+    impl Read for ChunkedReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.chunk.min(buf.len()).min(self.bytes.len());
+            buf[..n].copy_from_slice(&self.bytes[..n]);
+            self.bytes.drain(..n);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        let frame = Frame::new([0x79, 0x5f], vec![65, 65, 65]);
+
+        assert_eq!(
+            frame.encode().unwrap(),
+            vec![0x00, 0x06, 0x03, 0x00, 0x79, 0x5f, 0x00, 0x00, 65, 65, 65]
+        );
+    }
+
+    #[test]
+    fn test_encode_too_large() {
+        let frame = Frame::new([0, 0], vec![0; MAX_PAYLOAD + 1]);
+
+        assert_eq!(frame.encode(), Err(FrameError::TooLarge(MAX_PAYLOAD + 1)));
+    }
+
+    #[test]
+    fn test_decode_header() {
+        let head = [0x00, 0x06, 0x6f, 0x01, 0x79, 0x5f, 0x00, 0x00];
+        assert_eq!(Frame::decode_header(&head), Ok(([0x79, 0x5f], 367)));
+
+        let head = [0xcd, 0xab, 0x6f, 0x01, 0x79, 0x5f, 0x00, 0x00];
+        assert_eq!(
+            Frame::decode_header(&head),
+            Err(FrameError::InvalidMagic([0xcd, 0xab]))
+        );
+
+        let head = [0x00, 0x06, 0x6f, 0x01, 0x79, 0x5f, 0x01, 0x00];
+        assert_eq!(
+            Frame::decode_header(&head),
+            Err(FrameError::InvalidHeader(head))
+        );
+    }
+
+    #[test]
+    fn test_read_from_short_reads() {
+        let frame = Frame::new([1, 2], "A".repeat(300).into_bytes());
+        let mut reader = ChunkedReader {
+            bytes: frame.encode().unwrap(),
+            chunk: 3
+        };
+
+        assert_eq!(Frame::read_from(&mut reader).unwrap(), frame);
+    }
+
+    #[test]
+    fn test_read_from_invalid_magic() {
+        let mut reader = ChunkedReader {
+            bytes: vec![0xcd, 0xab, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            chunk: 16
+        };
+
+        let error = Frame::read_from(&mut reader).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
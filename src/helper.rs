@@ -1,11 +1,11 @@
 use rand::distributions::{Distribution, Uniform};
 
-const START: u8 = 0x01;
+pub const START: u8 = 0x01;
 
 // The reason why it stops at 0x80 is because in some parts of the
 // protocol the server adds 0x80 to the total, which could result in
 // a number higher than 0xff, which doesn't exist.
-const END: u8 = 0x80;
+pub const END: u8 = 0x80;
 
 pub struct Helper {}
 
@@ -28,4 +28,14 @@ impl Helper {
             die.sample(&mut rng)
         ]
     }
+
+    // Percent-encodes everything but the unreserved characters of
+    // RFC 3986, so the value can be put in any part of a URL.
+    pub fn url_encode(value: &str) -> String {
+        value.bytes().map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' =>
+                (b as char).to_string(),
+            _ => format!("%{:02X}", b)
+        }).collect()
+    }
 }
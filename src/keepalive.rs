@@ -0,0 +1,205 @@
+use std::io;
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use crate::channel::{ChannelHandle, ChannelKind};
+use crate::command::Command;
+use crate::connection::Connection;
+use crate::helper::Helper;
+use crate::ViperError;
+
+// The amount of pings in a row that can go unanswered before the
+// device is considered gone.
+const MAX_MISSES: u32 = 3;
+
+#[derive(Debug)]
+pub enum KeepaliveEvent {
+    // The device answered on the ECHO channel, with the round-trip time
+    Pong(Duration),
+    // A ping went unanswered, with the amount of misses in a row
+    Missed(u32),
+    // The device stopped answering, the client has to be replaced
+    Dead(ViperError),
+    // The ECHO channel couldn't be opened, so nothing is pinged. This
+    // says nothing about the connection itself.
+    Unavailable(ViperError)
+}
+
+/// Pings the device over the ECHO channel on a background thread, for
+/// as long as this handle is kept around.
+pub struct Keepalive {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl Keepalive {
+    pub(crate) fn spawn<F>(connection: Arc<Connection>,
+                           interval: Duration,
+                           mut on_event: F) -> Keepalive
+        where F: FnMut(KeepaliveEvent) + Send + 'static {
+
+        let (stop, stopped) = mpsc::channel();
+
+        let thread = thread::spawn(move || {
+            let echo = match connection.channel(ChannelKind::ECHO) {
+                Ok(echo) => echo,
+                Err(e) => return on_event(KeepaliveEvent::Unavailable(e))
+            };
+
+            if let Err(e) = Self::run(&echo, interval, &stopped, &mut on_event) {
+                connection.mark_dead();
+                on_event(KeepaliveEvent::Dead(e));
+            }
+        });
+
+        Keepalive {
+            stop: Some(stop),
+            thread: Some(thread)
+        }
+    }
+
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    // Only returns once the keepalive is stopped, or when the device
+    // has stopped answering.
+    fn run<F>(echo: &ChannelHandle,
+              interval: Duration,
+              stopped: &mpsc::Receiver<()>,
+              on_event: &mut F) -> Result<(), ViperError>
+        where F: FnMut(KeepaliveEvent) {
+
+        let mut misses = 0;
+
+        loop {
+            match stopped.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => {},
+                // The ECHO channel is closed when the handle drops
+                _ => return Ok(())
+            }
+
+            // A pong that came in after its ping was counted as missed
+            // would otherwise be taken as the reply to this one.
+            echo.discard();
+
+            let ping = Helper::gen_ran(4);
+            let start = Instant::now();
+            echo.write(&Command::template(&ping, &echo.control()))?;
+
+            match echo.read() {
+                Ok(_) => {
+                    misses = 0;
+                    on_event(KeepaliveEvent::Pong(start.elapsed()));
+                },
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                    misses += 1;
+                    if misses >= MAX_MISSES {
+                        return Err(ViperError::from(e))
+                    }
+
+                    on_event(KeepaliveEvent::Missed(misses));
+                },
+                Err(e) => return Err(ViperError::from(e))
+            }
+        }
+    }
+
+    fn shutdown(&mut self) {
+        // Dropping the sender wakes the thread up
+        self.stop.take();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Keepalive {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use crate::ViperClient;
+    use crate::test_helper::{SimpleTcpListener, OPENED};
+
+    #[test]
+    fn test_marks_dead() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3348");
+        let client = ViperClient::connect("127.0.0.1", "3348").unwrap();
+
+        // Answers two pings and then hangs up
+        thread::spawn(move || {
+            listener.mock_replies(vec![&OPENED, &[1, 2, 3, 4], &[1, 2, 3, 4]])
+        });
+
+        let (events, received) = mpsc::channel();
+        let _keepalive = client.keepalive(
+            Duration::from_millis(10),
+            move |event| { let _ = events.send(event); }
+        );
+
+        let timeout = Duration::from_secs(1);
+        assert!(matches!(received.recv_timeout(timeout), Ok(KeepaliveEvent::Pong(_))));
+        assert!(matches!(received.recv_timeout(timeout), Ok(KeepaliveEvent::Pong(_))));
+        assert!(matches!(received.recv_timeout(timeout), Ok(KeepaliveEvent::Dead(_))));
+        assert!(!client.is_alive());
+    }
+
+    #[test]
+    fn test_refused_echo_channel() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3365");
+        let client = ViperClient::connect("127.0.0.1", "3365").unwrap();
+
+        // Anything but an open ack refuses the channel
+        let server = thread::spawn(move || {
+            listener.serve(|_| vec![vec![0x00, 0x00, 0x00, 0x00]])
+        });
+
+        let (events, received) = mpsc::channel();
+        let keepalive = client.keepalive(
+            Duration::from_millis(10),
+            move |event| { let _ = events.send(event); }
+        );
+
+        let timeout = Duration::from_secs(1);
+        assert!(matches!(
+            received.recv_timeout(timeout),
+            Ok(KeepaliveEvent::Unavailable(ViperError::ChannelRefused(ChannelKind::ECHO, _)))
+        ));
+        assert!(client.is_alive());
+
+        keepalive.stop();
+        drop(client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_stop() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3349");
+        let client = ViperClient::connect("127.0.0.1", "3349").unwrap();
+
+        // Stays connected until the client hangs up
+        let server = thread::spawn(move || {
+            let mut replies = vec![OPENED.to_vec(), vec![]].into_iter();
+            listener.serve(|_| replies.next().into_iter().collect())
+        });
+
+        // The ECHO channel is opened before the first interval starts
+        let keepalive = client.keepalive(Duration::from_secs(60), |_| {});
+        keepalive.stop();
+        assert!(client.is_alive());
+        drop(client);
+
+        let requests = server.join().unwrap();
+        // The control bytes follow the channel name in an open request
+        let control = [requests[0].payload[12], requests[0].payload[13]];
+        assert_eq!(requests[1].payload, Command::close(&control)[8..]);
+    }
+}
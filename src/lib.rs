@@ -1,49 +1,116 @@
+mod call;
 mod channel;
+mod channel_allocator;
+mod client_builder;
+mod connection;
 mod ctpp_channel;
+mod door;
 mod helper;
+mod keepalive;
+mod multiplexer;
+mod session;
+pub mod frame;
+pub mod ctpp_packet;
 mod stream_wrapper;
+#[cfg(feature = "async")]
+mod async_stream_wrapper;
 pub mod device;
 pub mod command;
 pub mod command_response;
+#[cfg(feature = "async")]
+pub mod r#async;
 
 #[cfg(test)]
 mod test_helper;
 
 use serde::Deserialize;
 use stream_wrapper::StreamWrapper;
-use channel::Channel;
-use command::CommandKind;
+pub use client_builder::ViperClientBuilder;
+pub use session::{Backoff, ViperSession};
+pub use keepalive::{Keepalive, KeepaliveEvent};
+pub use call::{CallError, CallHandle, CallListener, CallState, Event};
+pub use door::{DoorOpenError, DoorOpenStep};
+pub use channel::{ChannelHandle, ChannelKind};
+use channel_allocator::ChannelAllocator;
+use connection::Connection;
+use command::{AddressBooks, Command, CommandKind, PushToken};
 use command_response::{
     ActivateUserResponse,
+    Actuator,
     AuthResponse,
     ConfigurationResponse,
+    FaceRecognitionParams,
+    FaceRecognitionParamsResponse,
     InfoResponse,
+    PushInfoResponse,
+    RemoveUserResponse,
+    SetFaceRecognitionParamsResponse,
+    UserListResponse,
     VipResponse
 };
 use ctpp_channel::CTPPChannel;
-use helper::Helper;
+use door::{DoorLookup, DoorOpening, DOOR_MODULE};
+use frame::FrameError;
 use std::{io, fmt, fmt::Display, str};
+use std::sync::{Arc, MutexGuard};
+use std::time::Duration;
 
 type JSONResult<T> = Result<T, ViperError>;
 
 pub struct ViperClient {
-    stream: StreamWrapper,
-    control: [u8; 2]
+    connection: Arc<Connection>,
+    info: Option<InfoResponse>,
+    configuration: Option<ConfigurationResponse>
 }
 
 #[derive(Debug)]
 pub enum ViperError {
+    ConnectError(io::Error),
     IOError(io::Error),
-    JSONError(serde_json::Error)
+    JSONError(serde_json::Error),
+    // A request that doesn't fit into a single frame
+    FrameError(FrameError),
+    NoChannelsAvailable,
+    Unauthorized(u16, String),
+    // The expected message and id, followed by the ones that were received
+    MismatchedResponse((String, u32), (String, u32)),
+    // The channel that was opened, with the reply of the device
+    ChannelRefused(ChannelKind, Vec<u8>),
+    // The channel that was requested, with the reason why it can't be used
+    Unsupported(ChannelKind, String),
+    DoorOpen(DoorOpenError),
+    Call(CallError)
 }
 
 impl Display for ViperError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            ViperError::ConnectError(io_error) =>
+                write!(f, "Unable to connect to the doorbell: {}", io_error),
             ViperError::IOError(io_error) =>
                 write!(f, "{}", io_error),
             ViperError::JSONError(json_error) =>
                 write!(f, "{}", json_error),
+            ViperError::FrameError(frame_error) =>
+                write!(f, "{}", frame_error),
+            ViperError::NoChannelsAvailable =>
+                write!(f, "All channels are in use"),
+            ViperError::Unauthorized(code, reason) =>
+                write!(f, "Authorization failed ({}): {}", code, reason),
+            ViperError::MismatchedResponse(expected, received) =>
+                write!(
+                    f,
+                    "Expected a response to {} (id {}), but received {} (id {})",
+                    expected.0, expected.1, received.0, received.1
+                ),
+            ViperError::ChannelRefused(channel, reply) =>
+                write!(f, "The device refused to open {}: {:02x?}", channel, reply),
+            ViperError::Unsupported(channel, reason) =>
+                write!(f, "The device doesn't support {}: {}", channel, reason),
+            ViperError::DoorOpen(door_error) =>
+                write!(f, "Unable to open the door: {}", door_error),
+            ViperError::Call(call_error) =>
+                write!(f, "Unable to act on the call: {}", call_error),
         }
     }
 }
@@ -54,126 +121,304 @@ impl From<io::Error> for ViperError {
     }
 }
 
+impl From<FrameError> for ViperError {
+    fn from(error: FrameError) -> Self {
+        ViperError::FrameError(error)
+    }
+}
+
+impl From<DoorOpenError> for ViperError {
+    fn from(error: DoorOpenError) -> Self {
+        ViperError::DoorOpen(error)
+    }
+}
+
+impl From<CallError> for ViperError {
+    fn from(error: CallError) -> Self {
+        ViperError::Call(error)
+    }
+}
+
 impl ViperClient {
-    pub fn new(ip: &String, port: &String) -> ViperClient {
-        let doorbell = format!("{}:{}", ip, port);
+    /// Connects with the default timeouts, use [`ViperClientBuilder`]
+    /// to configure them.
+    pub fn connect(ip: &str, port: &str) -> Result<ViperClient, ViperError> {
+        ViperClientBuilder::new(ip, port).connect()
+    }
 
+    pub(crate) fn from_stream(stream: StreamWrapper) -> ViperClient {
         ViperClient {
-            stream: StreamWrapper::new(doorbell),
-            control: Helper::control()
+            connection: Arc::new(Connection::new(stream)),
+            info: None,
+            configuration: None
         }
     }
 
     pub fn sign_up(&mut self, email: &String) -> JSONResult<ActivateUserResponse> {
-        let fact_channel = self.channel("FACT");
-        self.stream.execute(&fact_channel.open())?;
+        let fact_channel = self.channel(ChannelKind::FACT)?;
         let activate_user = CommandKind::ActivateUser(String::from(email));
-        let act_bytes = self.stream.execute(&fact_channel.com(activate_user))?;
-        let json_response = Self::json(&act_bytes);
+        let act_bytes = fact_channel.com(activate_user)?;
+        fact_channel.close()?;
 
-        self.stream.execute(&fact_channel.close())?;
-        json_response
+        Self::json(&act_bytes)
     }
 
     pub fn remove_all_users(&mut self, email: &String) -> JSONResult<serde_json::Value> {
-        let fact_channel = self.channel("FACT");
-        self.stream.execute(&fact_channel.open())?;
+        let fact_channel = self.channel(ChannelKind::FACT)?;
         let remove_all_users = CommandKind::RemoveAllUsers(String::from(email));
-        let rem_bytes = self.stream.execute(&fact_channel.com(remove_all_users))?;
-        self.stream.execute(&fact_channel.close())?;
+        let rem_bytes = fact_channel.com(remove_all_users)?;
+        fact_channel.close()?;
+
+        Self::json(&rem_bytes)
+    }
+
+    /// Lists every user that was activated on the device, with the
+    /// description it was activated with.
+    pub fn users(&mut self) -> JSONResult<UserListResponse> {
+        let uadm_channel = self.channel(ChannelKind::UADM)?;
+        let uadm_bytes = uadm_channel.com(CommandKind::ListUsers)?;
+        uadm_channel.close()?;
 
-        let json_response = Self::json(&rem_bytes);
-        json_response
+        Self::json(&uadm_bytes)
+    }
+
+    /// Revokes the token of a single user, unlike `remove_all_users`
+    /// every other user stays signed in.
+    pub fn remove_user(&mut self, user_token: &str) -> JSONResult<RemoveUserResponse> {
+        let remove_user = CommandKind::RemoveUser(user_token.to_string());
+        let uadm_channel = self.channel(ChannelKind::UADM)?;
+        let uadm_bytes = uadm_channel.com(remove_user)?;
+        uadm_channel.close()?;
+
+        Self::json(&uadm_bytes)
     }
 
     pub fn authorize(&mut self, token: String) -> JSONResult<AuthResponse> {
         let uaut = CommandKind::UAUT(token);
-        let uaut_channel = self.channel("UAUT");
-        self.stream.execute(&uaut_channel.open())?;
-        let uaut_bytes = self.stream.execute(&uaut_channel.com(uaut))?;
+        let uaut_channel = self.channel(ChannelKind::UAUT)?;
+        let uaut_bytes = uaut_channel.com(uaut)?;
+        uaut_channel.close()?;
 
-        let json_response = Self::json(&uaut_bytes);
-        self.stream.execute(&uaut_channel.close())?;
-        json_response
+        Self::json(&uaut_bytes)
     }
 
-    pub fn configuration(&mut self, addressbooks: String) -> JSONResult<ConfigurationResponse> {
+    pub fn configuration(&mut self, addressbooks: AddressBooks) -> JSONResult<ConfigurationResponse> {
         let ucfg = CommandKind::UCFG(addressbooks);
-        let ucfg_channel = self.channel("UCFG");
-        self.stream.execute(&ucfg_channel.open())?;
-        let ucfg_bytes = self.stream.execute(&ucfg_channel.com(ucfg))?;
+        let ucfg_channel = self.channel(ChannelKind::UCFG)?;
+        let ucfg_bytes = ucfg_channel.com(ucfg)?;
+        ucfg_channel.close()?;
+
+        Self::json(&ucfg_bytes)
+    }
+
+    pub fn register_push_token(&mut self, token: &PushToken) -> JSONResult<PushInfoResponse> {
+        let push = CommandKind::PushInfo(token.clone());
+        let push_channel = self.channel(ChannelKind::PUSH)?;
+        let push_bytes = push_channel.com(push)?;
+        push_channel.close()?;
 
-        let json_response = Self::json(&ucfg_bytes);
-        self.stream.execute(&ucfg_channel.close())?;
-        json_response
+        Self::json(&push_bytes)
     }
 
+    /// Stops the push notifications to the device of `token` by
+    /// registering it again with an empty device token. That this is how
+    /// the device unregisters a token is an assumption, it hasn't been
+    /// confirmed on a device yet.
+    pub fn unregister_push_token(&mut self, token: &PushToken) -> JSONResult<PushInfoResponse> {
+        let token = PushToken {
+            device_token: String::new(),
+            ..token.clone()
+        };
+
+        self.register_push_token(&token)
+    }
+
+    /// Always asks the device, but the response is kept around to check
+    /// whether the device supports a channel before it's opened.
     pub fn info(&mut self) -> JSONResult<InfoResponse> {
         let info = CommandKind::INFO;
-        let info_channel = self.channel("INFO");
-        self.stream.execute(&info_channel.open())?;
+        let info_channel = self.channel(ChannelKind::INFO)?;
+        let info_bytes = info_channel.com(info)?;
+        info_channel.close()?;
 
-        let info_bytes = self.stream.execute(&info_channel.com(info))?;
-        let json_response = Self::json(&info_bytes);
-        self.stream.execute(&info_channel.close())?;
-        json_response
+        let info: InfoResponse = Self::json(&info_bytes)?;
+        self.info = Some(info.clone());
+        Ok(info)
     }
 
-    pub fn face_recognition_params(&mut self) -> JSONResult<serde_json::Value> {
+    pub fn face_recognition_params(&mut self) -> JSONResult<FaceRecognitionParamsResponse> {
         let frcg = CommandKind::FRCG;
-        let frcg_channel = self.channel("FRCG");
-        self.stream.execute(&frcg_channel.open())?;
+        let frcg_channel = self.channel(ChannelKind::FRCG)?;
+        let frcg_bytes = frcg_channel.com(frcg)?;
+        frcg_channel.close()?;
 
-        let frcg_bytes = self.stream.execute(&frcg_channel.com(frcg))?;
-        let json_response = Self::json(&frcg_bytes);
-        self.stream.execute(&frcg_channel.close())?;
-        json_response
+        Self::json(&frcg_bytes)
     }
 
-    // TODO: This function is not finished
-    pub fn open_door(&mut self, vip: &VipResponse) -> Result<(), std::io::Error> {
-        let addr = vip.apt_address.to_string();
-        let sub = format!("{}{}", addr, vip.apt_subaddress);
-        let act = vip.user_parameters.opendoor_address_book[0].apt_address.to_string();
+    pub fn set_face_recognition_params(&mut self,
+                                       params: &FaceRecognitionParams) -> JSONResult<SetFaceRecognitionParamsResponse> {
 
-        let mut ctpp_channel = self.ctpp_channel();
-        self.stream.execute(&ctpp_channel.open(&sub))?;
-        self.stream.write(&ctpp_channel.connect_hs(&sub, &addr))?;
+        let frcg = CommandKind::SetFaceRecognitionParams(params.clone());
+        let frcg_channel = self.channel(ChannelKind::FRCG)?;
+        let frcg_bytes = frcg_channel.com(frcg)?;
+        frcg_channel.close()?;
 
-        loop {
-            let resp = self.stream.read()?;
-            println!("{:02x?}", resp);
-            if ctpp_channel.confirm_handshake(&resp) {
-                break;
+        Self::json(&frcg_bytes)
+    }
+
+    /// Sends a message that the client has no command for. The
+    /// `message-type` and `message-id` are filled in, everything else is
+    /// sent as it is.
+    pub fn request_json(&mut self,
+                        kind: ChannelKind,
+                        request: serde_json::Value) -> JSONResult<serde_json::Value> {
+
+        let message = Command::message_of(&request)?;
+        let channel = self.channel(kind)?;
+        let control = channel.control();
+        let bytes = channel.exchange(
+            &message,
+            |message_id| Command::json(request, message_id, &control)
+        )?;
+        channel.close()?;
+
+        Self::json(&bytes)
+    }
+
+    /// Opens the first door of the configuration. Only returns `Ok` once
+    /// the device has acknowledged it, a [`ViperError::DoorOpen`] tells
+    /// which step of the CTPP exchange it got stuck on.
+    pub fn open_door(&mut self, vip: &VipResponse) -> Result<(), ViperError> {
+        let door = DoorOpening::first_door(vip)?;
+        self.link_actuator(vip, &door.actuator_id(), DOOR_MODULE)
+    }
+
+    /// Opens the door or door action with this name. The configuration
+    /// is fetched the first time a door is opened and reused after that.
+    pub fn open_door_by_name(&mut self, name: &str) -> Result<(), ViperError> {
+        self.open_door_by(DoorLookup::Name(name))
+    }
+
+    pub fn open_door_by_id(&mut self, id: &str) -> Result<(), ViperError> {
+        self.open_door_by(DoorLookup::Id(id))
+    }
+
+    /// Triggers an actuator out of the `actuator_address_book` or the
+    /// `additional_actuator` list of the configuration, like a light or
+    /// a relay. This goes over CTPP the same way a door is opened.
+    pub fn activate_actuator(&mut self, actuator: &Actuator) -> Result<(), ViperError> {
+        self.with_configuration(|client, config| {
+            client.link_actuator(&config.vip, &actuator.actuator_id(), actuator.module_index)
+        })
+    }
+
+    fn open_door_by(&mut self, lookup: DoorLookup<'_>) -> Result<(), ViperError> {
+        self.with_configuration(|client, config| {
+            let actuator_id = lookup.actuator_id(&config.vip.user_parameters)?;
+            client.link_actuator(&config.vip, &actuator_id, DOOR_MODULE)
+        })
+    }
+
+    // The configuration is put back afterwards, whatever the outcome
+    fn with_configuration<T, F>(&mut self, f: F) -> Result<T, ViperError>
+        where F: FnOnce(&mut Self, &ConfigurationResponse) -> Result<T, ViperError> {
+
+        let config = match self.configuration.take() {
+            Some(config) => config,
+            None => self.configuration(AddressBooks::All)?
+        };
+
+        let result = f(self, &config);
+        self.configuration = Some(config);
+        result
+    }
+
+    fn link_actuator(&mut self,
+                     vip: &VipResponse,
+                     actuator_id: &str,
+                     module_index: u8) -> Result<(), ViperError> {
+
+        let (mut channel, ctpp_channel) = self.ctpp_channel()?;
+        let mut door = DoorOpening::new(ctpp_channel, vip, actuator_id, module_index);
+
+        channel.open_with(&door.open())?;
+        channel.write(&door.handshake())?;
+
+        while !door.is_opened() {
+            let reply = match channel.read() {
+                Ok(reply) => reply,
+                Err(e) if e.kind() == io::ErrorKind::TimedOut =>
+                    return Err(ViperError::from(door.timed_out())),
+                Err(e) => return Err(ViperError::from(e))
+            };
+
+            for request in door.receive(&reply)? {
+                channel.write(&request)?;
             }
         }
 
-        self.stream.write(&ctpp_channel.ack(0x00, &sub, &addr))?;
-        self.stream.write(&ctpp_channel.ack(0x20, &sub, &addr))?;
-        self.stream.write(&ctpp_channel.link_actuators(&act, &sub))?;
+        channel.close()
+    }
 
-        let resp = self.stream.read()?;
-        if ctpp_channel.confirm(&resp) {
-            // ????
-        } else {
-            // raise an error
-        }
+    /// Starts pinging the device over the ECHO channel every `interval`.
+    /// Once the device stops answering the client is marked as dead and
+    /// `on_event` receives a [`KeepaliveEvent::Dead`]. When the ECHO
+    /// channel can't be opened it receives a
+    /// [`KeepaliveEvent::Unavailable`] instead, and the client is left as is.
+    pub fn keepalive<F>(&self, interval: Duration, on_event: F) -> Keepalive
+        where F: FnMut(KeepaliveEvent) + Send + 'static {
+
+        Keepalive::spawn(Arc::clone(&self.connection), interval, on_event)
+    }
+
+    /// Listens for rings at the entrances on a background thread.
+    /// Every incoming call for the apartment of `vip` is passed to
+    /// `on_event` as an [`Event::IncomingCall`].
+    pub fn listen<F>(&self, vip: &VipResponse, on_event: F) -> CallListener
+        where F: FnMut(Event) + Send + 'static {
 
-        // Close the remaining channels
-        self.stream.execute(&ctpp_channel.close())?;
-        Ok(())
+        CallListener::spawn(Arc::clone(&self.connection), vip, on_event)
     }
 
-    fn channel(&mut self, command: &'static str) -> Channel {
-        self.tick();
+    pub fn is_alive(&self) -> bool {
+        self.connection.is_alive()
+    }
 
-        Channel::new(&self.control, command)
+    /// Opens a channel for requests the client has no method for. The
+    /// channel is closed again once the handle is dropped.
+    pub fn channel(&mut self, kind: ChannelKind) -> Result<ChannelHandle<'_>, ViperError> {
+        self.require(kind)?;
+        self.connection.channel(kind)
     }
 
-    fn ctpp_channel(&mut self) -> CTPPChannel {
-        self.tick();
+    // INFO is only fetched once per connection, the first time a channel
+    // with a capability is opened.
+    fn require(&mut self, kind: ChannelKind) -> Result<(), ViperError> {
+        if kind.capability().is_none() {
+            return Ok(())
+        }
+
+        if self.info.is_none() {
+            self.info()?;
+        }
 
-        CTPPChannel::new(&self.control)
+        match &self.info {
+            Some(info) => info.require(kind),
+            None => Ok(())
+        }
+    }
+
+    // The CTPP channel is opened with the apartment address, which is
+    // why opening it is left to the caller.
+    fn ctpp_channel(&self) -> Result<(ChannelHandle<'_>, CTPPChannel), ViperError> {
+        let handle = self.connection.handle(ChannelKind::CTPP)?;
+        let ctpp_channel = CTPPChannel::new(&handle.control());
+
+        Ok((handle, ctpp_channel))
+    }
+
+    fn allocator(&self) -> MutexGuard<'_, ChannelAllocator> {
+        self.connection.allocator()
     }
 
     fn json<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> JSONResult<T> {
@@ -183,13 +428,26 @@ impl ViperClient {
         }
     }
 
-    pub fn shutdown(&mut self) {
-        self.stream.die();
-    }
+    // Closes every channel that is still open before the connection
+    // is torn down. The connection is always closed, even when closing
+    // one of the channels fails.
+    pub fn shutdown(&mut self) -> Result<(), ViperError> {
+        let mut result = Ok(());
+
+        let open_channels = self.allocator().open_channels();
 
-    // Move the control byte 1 ahead
-    fn tick(&mut self) {
-        self.control[0] += 1
+        for control in open_channels {
+            if let Err(e) = self.connection.stream.execute(&Command::close(&control)) {
+                result = Err(ViperError::from(e));
+                break;
+            }
+
+            self.connection.stream.discard(&control);
+            self.allocator().release(&control);
+        }
+
+        self.connection.stream.die();
+        result
     }
 }
 
@@ -197,50 +455,78 @@ impl ViperClient {
 mod tests {
     use super::*;
     use std::thread;
-    use crate::command::Command;
-    use crate::test_helper::SimpleTcpListener;
+    use std::time::Instant;
+    use crate::frame::Frame;
+    use crate::test_helper::{
+        confirm,
+        with_message_id,
+        SimpleTcpListener,
+        CONFIGURATION_RESPONSE,
+        INFO_RESPONSE,
+        OPENED
+    };
 
     #[test]
-    fn test_tick() {
-        let _listener = SimpleTcpListener::new("127.0.0.1:3340");
-        let mut client = ViperClient::new(
-            &String::from("127.0.0.1"),
-            &String::from("3340")
-        );
+    fn test_shutdown() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3340");
+        let mut client = ViperClient::connect("127.0.0.1", "3340").unwrap();
+
+        let server = thread::spawn(move || {
+            listener.mock_replies(vec![&[], &[]]).unwrap()
+        });
 
-        let c = client.control;
-        client.tick();
+        // Channels that are left open, like when their handle is leaked
+        let info = client.allocator().allocate().unwrap();
+        let uaut = client.allocator().allocate().unwrap();
+        assert_ne!(info, uaut);
 
-        assert_eq!(c[0] + 1, client.control[0])
+        client.shutdown().unwrap();
+        assert!(client.allocator().open_channels().is_empty());
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0].payload, Command::close(&info)[8..]);
+        assert_eq!(requests[1].payload, Command::close(&uaut)[8..]);
+    }
+
+    #[test]
+    fn test_dead_after_hang_up() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3364");
+        let client = ViperClient::connect("127.0.0.1", "3364").unwrap();
+
+        // Hangs up right away, without a keepalive running
+        thread::spawn(move || listener.mock_replies(vec![])).join().unwrap().unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while client.is_alive() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!(!client.is_alive());
     }
 
     #[test]
     fn test_authorize() {
         let listener = SimpleTcpListener::new("127.0.0.1:3341");
-        let mut client = ViperClient::new(
-            &String::from("127.0.0.1"),
-            &String::from("3341")
-        );
+        let mut client = ViperClient::connect("127.0.0.1", "3341").unwrap();
 
         thread::spawn(move || {
-            let mocked_open = [
-                0xcd, 0xab, 0x02, 0x00, 0x04, 0x00, 0x00, 0x00,
-                0x1a, 0x12, 0x00, 0x00
-            ];
-
             let mocked_json = r#"{
                 "message":"access",
                 "message-type":"response",
-                "message-id":5,
+                "message-id":2,
                 "response-code":200,
                 "response-string":"Access Granted"
             }"#;
 
-            listener.mock_server(
+            // INFO is asked first, to see if UAUT is supported
+            listener.mock_replies(
                 vec![
-                    Command::make(&mocked_open, &[0, 0]),
-                    Command::make(&mocked_json.as_bytes(), &[0, 0]),
-                    Command::make(&[], &[0, 0]) // Closing the channel
+                    &OPENED,
+                    INFO_RESPONSE.as_bytes(),
+                    &[],
+                    &OPENED,
+                    mocked_json.as_bytes(),
+                    &[] // Closing the channel
                 ]
             )
         });
@@ -249,4 +535,394 @@ mod tests {
         assert_eq!(resp.response.response_string, "Access Granted");
         assert_eq!(resp.response.response_code, 200)
     }
+
+    #[test]
+    fn test_mismatched_response() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3350");
+        let mut client = ViperClient::connect("127.0.0.1", "3350").unwrap();
+
+        thread::spawn(move || {
+            // The second request gets the response to the first one
+            let info = with_message_id(INFO_RESPONSE, 1);
+
+            listener.mock_replies(
+                vec![
+                    &OPENED, &info, &[],
+                    &OPENED, &info, &[]
+                ]
+            )
+        });
+
+        assert_eq!(client.info().unwrap().response.message_id, 1);
+
+        match client.info() {
+            Err(ViperError::MismatchedResponse(expected, received)) => {
+                assert_eq!(expected, (String::from("server-info"), 2));
+                assert_eq!(received, (String::from("server-info"), 1));
+            },
+            other => panic!("Expected a mismatched response, got {:?}", other)
+        }
+
+        // The channel is still closed after the mismatch
+        assert!(client.allocator().open_channels().is_empty());
+    }
+
+    #[test]
+    fn test_channel_refused() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3351");
+        let mut client = ViperClient::connect("127.0.0.1", "3351").unwrap();
+
+        thread::spawn(move || listener.mock_replies(vec![&[0xff, 0xff]]));
+
+        match client.info() {
+            Err(ViperError::ChannelRefused(channel, reply)) => {
+                assert_eq!(channel, ChannelKind::INFO);
+                assert_eq!(reply, vec![0xff, 0xff]);
+            },
+            other => panic!("Expected a refused channel, got {:?}", other)
+        }
+
+        assert!(client.allocator().open_channels().is_empty());
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3352");
+        let mut client = ViperClient::connect("127.0.0.1", "3352").unwrap();
+
+        let server = thread::spawn(move || {
+            let mut info: serde_json::Value = serde_json::from_str(INFO_RESPONSE).unwrap();
+            info["capabilities"] = serde_json::json!(["user-auth-channel"]);
+            let info = serde_json::to_vec(&info).unwrap();
+
+            listener.mock_replies(vec![&OPENED, &info, &[]]).unwrap()
+        });
+
+        for _ in 0..2 {
+            match client.face_recognition_params() {
+                Err(ViperError::Unsupported(channel, _)) =>
+                    assert_eq!(channel, ChannelKind::FRCG),
+                other => panic!("Expected an unsupported channel, got {:?}", other)
+            }
+        }
+
+        // INFO is only asked once, and FRCG is never opened
+        assert_eq!(server.join().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_push_token() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3353");
+        let mut client = ViperClient::connect("127.0.0.1", "3353").unwrap();
+
+        let server = thread::spawn(move || {
+            let push_info = r#"{
+                "message":"push-info",
+                "message-type":"response",
+                "message-id":1,
+                "response-code":200,
+                "response-string":"OK"
+            }"#;
+            let registered = with_message_id(push_info, 2);
+            let unregistered = with_message_id(push_info, 3);
+
+            listener.mock_replies(
+                vec![
+                    &OPENED, INFO_RESPONSE.as_bytes(), &[],
+                    &OPENED, &registered, &[],
+                    &OPENED, &unregistered, &[]
+                ]
+            ).unwrap()
+        });
+
+        let token = PushToken {
+            apt_address: String::from("SB000006"),
+            apt_subaddress: 2,
+            bundle_id: String::from("com.comelitgroup.friendhome"),
+            os_type: String::from("ios"),
+            profile_id: String::from("3"),
+            device_token: String::from("abcdef")
+        };
+
+        let resp = client.register_push_token(&token).unwrap();
+        assert_eq!(resp.response.response_code, 200);
+
+        let resp = client.unregister_push_token(&token).unwrap();
+        assert_eq!(resp.response.message_id, 3);
+
+        let requests = server.join().unwrap();
+        assert_eq!(&requests[3].payload[8..12], b"PUSH");
+
+        let registered: serde_json::Value = serde_json::from_slice(&requests[4].payload).unwrap();
+        assert_eq!(registered["device-token"], "abcdef");
+
+        let unregistered: serde_json::Value = serde_json::from_slice(&requests[7].payload).unwrap();
+        assert_eq!(unregistered["device-token"], "");
+        assert_eq!(unregistered["apt-address"], "SB000006");
+    }
+
+    #[test]
+    fn test_user_admin() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3354");
+        let mut client = ViperClient::connect("127.0.0.1", "3354").unwrap();
+
+        let server = thread::spawn(move || {
+            let users = r#"{
+                "message":"list-users",
+                "message-type":"response",
+                "message-id":2,
+                "response-code":200,
+                "response-string":"OK",
+                "users":[
+                    {"user-token":"AAAA","description":"phone","email":"a@test.com"},
+                    {"user-token":"BBBB","description":"tablet"}
+                ]
+            }"#;
+
+            let removed = r#"{
+                "message":"remove-user",
+                "message-type":"response",
+                "message-id":3,
+                "response-code":200,
+                "response-string":"OK"
+            }"#;
+
+            listener.mock_replies(
+                vec![
+                    &OPENED, INFO_RESPONSE.as_bytes(), &[],
+                    &OPENED, users.as_bytes(), &[],
+                    &OPENED, removed.as_bytes(), &[]
+                ]
+            ).unwrap()
+        });
+
+        let users = client.users().unwrap().users;
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[0].description.as_deref(), Some("phone"));
+        assert_eq!(users[1].email, None);
+
+        let resp = client.remove_user(users[1].user_token.as_deref().unwrap()).unwrap();
+        assert_eq!(resp.response.response_code, 200);
+
+        let requests = server.join().unwrap();
+        assert_eq!(&requests[6].payload[8..12], b"UADM");
+
+        let removed: serde_json::Value = serde_json::from_slice(&requests[7].payload).unwrap();
+        assert_eq!(removed["message"], "remove-user");
+        assert_eq!(removed["user-token"], "BBBB");
+    }
+
+    #[test]
+    fn test_face_recognition_params() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3355");
+        let mut client = ViperClient::connect("127.0.0.1", "3355").unwrap();
+
+        let server = thread::spawn(move || {
+            let params = r#"{
+                "message":"rcg-get-params",
+                "message-type":"response",
+                "message-id":2,
+                "response-code":200,
+                "response-string":"OK",
+                "enabled":true,
+                "threshold":80,
+                "timeout":10,
+                "mode":"strict"
+            }"#;
+
+            let set = r#"{
+                "message":"rcg-set-params",
+                "message-type":"response",
+                "message-id":3,
+                "response-code":200,
+                "response-string":"OK"
+            }"#;
+
+            listener.mock_replies(
+                vec![
+                    &OPENED, INFO_RESPONSE.as_bytes(), &[],
+                    &OPENED, params.as_bytes(), &[],
+                    &OPENED, set.as_bytes(), &[]
+                ]
+            ).unwrap()
+        });
+
+        let mut params = client.face_recognition_params().unwrap().params;
+        assert_eq!(params.enabled(), Some(true));
+        assert_eq!(params.threshold(), Some(80));
+        assert_eq!(params.timeout(), Some(10));
+        assert_eq!(params.settings.len(), 4);
+
+        params.set_enabled(false);
+        let resp = client.set_face_recognition_params(&params).unwrap();
+        assert_eq!(resp.response.response_code, 200);
+
+        let requests = server.join().unwrap();
+        let set: serde_json::Value = serde_json::from_slice(&requests[7].payload).unwrap();
+        assert_eq!(set["message"], "rcg-set-params");
+        assert_eq!(set["enabled"], false);
+        assert_eq!(set["threshold"], 80);
+        assert_eq!(set["mode"], "strict");
+        assert_eq!(set["message-id"], 3);
+        assert!(set.get("response-code").is_none());
+    }
+
+    #[test]
+    fn test_request_json() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3356");
+        let mut client = ViperClient::connect("127.0.0.1", "3356").unwrap();
+
+        let server = thread::spawn(move || {
+            listener.mock_replies(vec![&OPENED, INFO_RESPONSE.as_bytes(), &[]]).unwrap()
+        });
+
+        // Nothing is sent without a message
+        assert!(matches!(
+            client.request_json(ChannelKind::INFO, serde_json::json!({})),
+            Err(ViperError::JSONError(_))
+        ));
+
+        let resp = client.request_json(
+            ChannelKind::INFO,
+            serde_json::json!({ "message": "server-info" })
+        ).unwrap();
+        assert_eq!(resp["model"], "MSVF");
+
+        let requests = server.join().unwrap();
+        let request: serde_json::Value = serde_json::from_slice(&requests[1].payload).unwrap();
+        assert_eq!(request["message-type"], "request");
+        assert_eq!(request["message-id"], 1);
+    }
+
+    #[test]
+    fn test_open_door() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3357");
+        let mut client = ViperClient::connect("127.0.0.1", "3357").unwrap();
+
+        let server = thread::spawn(move || {
+            listener.serve(|request| {
+                let payload = &request.payload;
+
+                match (payload[0], payload[6..8] == [0x00, 0x11]) {
+                    (0xcd, _) => vec![OPENED.to_vec()],
+                    // The handshake is acknowledged before it's answered
+                    (0xc0, true) => vec![
+                        vec![0x00, 0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+                        confirm(0x60, payload)
+                    ],
+                    (0xc0, false) => vec![confirm(0x00, payload)],
+                    (0xef, _) => vec![vec![]],
+                    _ => vec![]
+                }
+            })
+        });
+
+        let config: ConfigurationResponse = serde_json::from_str(CONFIGURATION_RESPONSE).unwrap();
+        client.open_door(&config.vip).unwrap();
+        client.shutdown().unwrap();
+
+        let requests = server.join().unwrap();
+        let link = &requests[4].payload;
+        assert_eq!(&link[0..2], &[0xc0, 0x18]);
+        assert_eq!(&link[10..19], b"SB1000011");
+        assert_eq!(&requests[5].payload[0..2], &[0xef, 0x01]);
+    }
+
+    // Plays the device while a door or actuator is linked, after the
+    // INFO and UCFG requests are answered.
+    fn ctpp_server(listener: SimpleTcpListener) -> thread::JoinHandle<Vec<Frame>> {
+        thread::spawn(move || {
+            let mut json = vec![
+                with_message_id(INFO_RESPONSE, 1),
+                with_message_id(CONFIGURATION_RESPONSE, 2)
+            ].into_iter();
+
+            listener.serve(|request| {
+                let payload = &request.payload;
+
+                match (payload[0], payload[6..8] == [0x00, 0x11]) {
+                    (0xcd, _) => vec![OPENED.to_vec()],
+                    (b'{', _) => vec![json.next().unwrap()],
+                    (0xc0, true) => vec![confirm(0x60, payload)],
+                    (0xc0, false) => vec![confirm(0x00, payload)],
+                    (0xef, _) => vec![vec![]],
+                    _ => vec![]
+                }
+            })
+        })
+    }
+
+    // The link is the only 0xc0 request that isn't a handshake
+    fn linked(requests: &[Frame]) -> Vec<&[u8]> {
+        requests
+            .iter()
+            .map(|request| &request.payload[..])
+            .filter(|payload| payload[0] == 0xc0 && payload[6..8] != [0x00, 0x11])
+            .collect()
+    }
+
+    #[test]
+    fn test_open_door_by_name() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3358");
+        let mut client = ViperClient::connect("127.0.0.1", "3358").unwrap();
+        let server = ctpp_server(listener);
+
+        client.open_door_by_name("Back door").unwrap();
+
+        // The configuration is only fetched once
+        match client.open_door_by_id("5") {
+            Err(ViperError::DoorOpen(DoorOpenError::UnknownDoor(id, doors))) => {
+                assert_eq!(id, "5");
+                assert_eq!(doors.len(), 2);
+            },
+            other => panic!("Expected an unknown door, got {:?}", other)
+        }
+        client.shutdown().unwrap();
+
+        let requests = server.join().unwrap();
+        let links = linked(&requests);
+
+        assert_eq!(links.len(), 1);
+        assert_eq!(&links[0][10..19], b"SB1000022");
+        assert_eq!(links[0][30], DOOR_MODULE);
+        assert_eq!(requests.iter().filter(|request| request.payload[0] == b'{').count(), 2);
+    }
+
+    #[test]
+    fn test_activate_actuator() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3359");
+        let mut client = ViperClient::connect("127.0.0.1", "3359").unwrap();
+        let server = ctpp_server(listener);
+
+        let config: ConfigurationResponse = serde_json::from_str(CONFIGURATION_RESPONSE).unwrap();
+        let light = &config.vip.user_parameters.actuator_address_book[0];
+
+        client.activate_actuator(light).unwrap();
+        client.shutdown().unwrap();
+
+        let requests = server.join().unwrap();
+        let links = linked(&requests);
+
+        assert_eq!(&links[0][10..19], b"SB0000012");
+        assert_eq!(links[0][30], 1);
+    }
+
+    #[test]
+    fn test_activate_additional_actuator() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3360");
+        let mut client = ViperClient::connect("127.0.0.1", "3360").unwrap();
+        let server = ctpp_server(listener);
+
+        let config: ConfigurationResponse = serde_json::from_str(CONFIGURATION_RESPONSE).unwrap();
+        let relay = &config.vip.user_parameters.additional_actuator[0];
+
+        client.activate_actuator(relay).unwrap();
+        client.shutdown().unwrap();
+
+        let requests = server.join().unwrap();
+        let links = linked(&requests);
+
+        assert_eq!(&links[0][10..19], b"SB0000021");
+        assert_eq!(links[0][30], 3);
+    }
 }
@@ -0,0 +1,235 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::io::prelude::*;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use crate::frame::Frame;
+
+// The device sends frames nobody asked for, like CTPP traffic while no
+// one listens, and late replies can come in after a channel is closed.
+// Nothing reads those, so only the most recent ones are kept.
+const MAX_QUEUED: usize = 64;
+
+// Multiple channels can be open at the same time (CTPP, UDPM and RTPC
+// during a video setup for example), and the device is free to send a
+// frame for any of them at any time. Every frame that is read is put on
+// a queue for the control bytes found in its header, so each channel only
+// ever sees the frames that belong to it.
+#[derive(Default)]
+struct State {
+    frames: HashMap<[u8; 2], VecDeque<Frame>>,
+    closed: Option<(io::ErrorKind, String)>
+}
+
+#[derive(Default)]
+pub struct Queues {
+    state: Mutex<State>,
+    available: Condvar,
+    #[cfg(feature = "async")]
+    notify: tokio::sync::Notify
+}
+
+impl Queues {
+    pub fn push(&self, frame: Frame) {
+        {
+            let mut state = self.lock();
+            let queue = state.frames.entry(frame.channel).or_default();
+
+            if queue.len() >= MAX_QUEUED {
+                queue.pop_front();
+            }
+
+            queue.push_back(frame);
+        }
+
+        self.wake();
+    }
+
+    // Once the reader stops, every pending and future receive fails
+    // with the error that stopped it.
+    pub fn fail(&self, error: &io::Error) {
+        self.lock().closed = Some((error.kind(), error.to_string()));
+        self.wake();
+    }
+
+    pub fn discard(&self, channel: &[u8; 2]) {
+        self.lock().frames.remove(channel);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.lock().closed.is_some()
+    }
+
+    pub fn recv(&self, channel: &[u8; 2], timeout: Duration) -> io::Result<Frame> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+
+        loop {
+            if let Some(result) = Self::pop(&mut state, channel) {
+                return result
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::from(io::ErrorKind::TimedOut))
+            }
+
+            state = self.available
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn recv_async(&self, channel: &[u8; 2], timeout: Duration) -> io::Result<Frame> {
+        let deadline = tokio::time::Instant::now() + timeout;
+
+        loop {
+            // Register interest before checking the queue, otherwise a
+            // frame that arrives in between is never noticed.
+            let notified = self.notify.notified();
+
+            if let Some(result) = Self::pop(&mut self.lock(), channel) {
+                return result
+            }
+
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                return Err(io::Error::from(io::ErrorKind::TimedOut))
+            }
+        }
+    }
+
+    fn pop(state: &mut State, channel: &[u8; 2]) -> Option<io::Result<Frame>> {
+        if let Some(frame) = state.frames.get_mut(channel).and_then(|q| q.pop_front()) {
+            return Some(Ok(frame))
+        }
+
+        state.closed
+            .as_ref()
+            .map(|(kind, message)| Err(io::Error::new(*kind, message.clone())))
+    }
+
+    fn wake(&self) {
+        self.available.notify_all();
+
+        #[cfg(feature = "async")]
+        self.notify.notify_waiters();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Reads frames off a stream on a background thread and routes them to
+/// a queue per channel.
+pub struct Multiplexer {
+    queues: Arc<Queues>,
+    reader: Option<JoinHandle<()>>
+}
+
+impl Multiplexer {
+    pub fn spawn<R: Read + Send + 'static>(mut stream: R) -> Multiplexer {
+        let queues = Arc::new(Queues::default());
+        let thread_queues = Arc::clone(&queues);
+
+        let reader = thread::spawn(move || {
+            loop {
+                match Frame::read_from(&mut stream) {
+                    Ok(frame) => thread_queues.push(frame),
+                    Err(e) => {
+                        thread_queues.fail(&e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        Multiplexer {
+            queues,
+            reader: Some(reader)
+        }
+    }
+
+    pub fn recv(&self, channel: &[u8; 2], timeout: Duration) -> io::Result<Frame> {
+        self.queues.recv(channel, timeout)
+    }
+
+    pub fn discard(&self, channel: &[u8; 2]) {
+        self.queues.discard(channel)
+    }
+
+    // The reader stops on EOF or the first error it runs into
+    pub fn is_closed(&self) -> bool {
+        self.queues.is_closed()
+    }
+
+    // The reader thread only stops once the stream it reads from is
+    // closed, so the owner has to shut the stream down first.
+    pub fn join(&mut self) {
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routes_frames_per_channel() {
+        let bytes = [
+            Frame::new([1, 1], vec![1]).encode().unwrap(),
+            Frame::new([2, 2], vec![2]).encode().unwrap(),
+            Frame::new([1, 1], vec![3]).encode().unwrap(),
+        ].concat();
+
+        let mut multiplexer = Multiplexer::spawn(io::Cursor::new(bytes));
+        let timeout = Duration::from_millis(100);
+
+        assert_eq!(multiplexer.recv(&[2, 2], timeout).unwrap().payload, vec![2]);
+        assert_eq!(multiplexer.recv(&[1, 1], timeout).unwrap().payload, vec![1]);
+        assert_eq!(multiplexer.recv(&[1, 1], timeout).unwrap().payload, vec![3]);
+
+        // The cursor is exhausted, so the reader has stopped
+        let error = multiplexer.recv(&[1, 1], timeout).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(multiplexer.is_closed());
+        multiplexer.join();
+    }
+
+    #[test]
+    fn test_recv_timeout() {
+        let queues = Queues::default();
+        queues.push(Frame::new([1, 1], vec![]));
+
+        let error = queues.recv(&[2, 2], Duration::from_millis(10)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(Queues::pop(&mut queues.lock(), &[1, 1]).is_some());
+    }
+
+    #[test]
+    fn test_queue_is_capped() {
+        let queues = Queues::default();
+
+        for i in 0..=MAX_QUEUED {
+            queues.push(Frame::new([1, 1], vec![i as u8]));
+        }
+
+        let mut state = queues.lock();
+        assert_eq!(state.frames[&[1, 1]].len(), MAX_QUEUED);
+        assert_eq!(Queues::pop(&mut state, &[1, 1]).unwrap().unwrap().payload, vec![1]);
+    }
+
+    #[test]
+    fn test_discard() {
+        let queues = Queues::default();
+        queues.push(Frame::new([1, 1], vec![]));
+        queues.discard(&[1, 1]);
+
+        assert!(Queues::pop(&mut queues.lock(), &[1, 1]).is_none());
+    }
+}
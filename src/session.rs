@@ -0,0 +1,287 @@
+use std::thread;
+use std::time::Duration;
+use crate::command::AddressBooks;
+use crate::command_response::{
+    ConfigurationResponse,
+    FaceRecognitionParamsResponse,
+    InfoResponse
+};
+use crate::{JSONResult, ViperClient, ViperClientBuilder, ViperError};
+
+const INITIAL_DELAY: u64 = 500;
+const MAX_DELAY: u64 = 30_000;
+const ATTEMPTS: u32 = 8;
+
+/// How often and how long to wait between connection attempts. The
+/// delay doubles after every failed attempt, up to `max_delay`.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub attempts: u32
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff {
+            initial_delay: Duration::from_millis(INITIAL_DELAY),
+            max_delay: Duration::from_millis(MAX_DELAY),
+            attempts: ATTEMPTS
+        }
+    }
+}
+
+impl Backoff {
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt);
+
+        self.initial_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+}
+
+/// Keeps an authorized [`ViperClient`] around for as long as the
+/// session lives. The Mini Wi-Fi drops off the network when it goes to
+/// sleep, so whenever the connection turns out to be gone the session
+/// reconnects and authorizes again with the same token.
+///
+/// Requests that don't change anything on the device (INFO, UCFG and
+/// FRCG) are retried once on a fresh connection, everything else is left
+/// to the caller through [`ViperSession::client`].
+pub struct ViperSession {
+    builder: ViperClientBuilder,
+    token: String,
+    backoff: Backoff,
+    client: Option<ViperClient>
+}
+
+impl ViperSession {
+    // Nothing is connected until the first request is made
+    pub fn new(builder: ViperClientBuilder, token: &str) -> ViperSession {
+        ViperSession {
+            builder,
+            token: token.to_string(),
+            backoff: Backoff::default(),
+            client: None
+        }
+    }
+
+    pub fn backoff(mut self, backoff: Backoff) -> ViperSession {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.client.as_ref().is_some_and(|client| client.is_alive())
+    }
+
+    pub fn info(&mut self) -> JSONResult<InfoResponse> {
+        self.retry(|client| client.info())
+    }
+
+    pub fn configuration(&mut self, addressbooks: AddressBooks) -> JSONResult<ConfigurationResponse> {
+        self.retry(|client| client.configuration(addressbooks.clone()))
+    }
+
+    pub fn face_recognition_params(&mut self) -> JSONResult<FaceRecognitionParamsResponse> {
+        self.retry(|client| client.face_recognition_params())
+    }
+
+    /// Returns the authorized client, (re)connecting when needed. When a
+    /// request on the client fails because of the connection, call
+    /// [`ViperSession::disconnect`] so the next call starts over.
+    pub fn client(&mut self) -> Result<&mut ViperClient, ViperError> {
+        // A keepalive on the client might have found it to be dead
+        if !self.is_connected() {
+            self.client = Some(self.reconnect()?);
+        }
+
+        Ok(self.client.as_mut().unwrap())
+    }
+
+    pub fn disconnect(&mut self) -> Result<(), ViperError> {
+        match self.client.take() {
+            Some(mut client) => client.shutdown(),
+            None => Ok(())
+        }
+    }
+
+    fn retry<T, F>(&mut self, request: F) -> Result<T, ViperError>
+        where F: Fn(&mut ViperClient) -> Result<T, ViperError> {
+
+        match request(self.client()?) {
+            Err(e) if Self::is_disconnect(&e) => {
+                self.client = None;
+
+                let result = request(self.client()?);
+                if matches!(&result, Err(e) if Self::is_disconnect(e)) {
+                    self.client = None;
+                }
+
+                result
+            },
+            result => result
+        }
+    }
+
+    fn reconnect(&self) -> Result<ViperClient, ViperError> {
+        let mut attempt = 0;
+
+        loop {
+            match self.authorized_client() {
+                Err(e) if Self::is_disconnect(&e) && attempt + 1 < self.backoff.attempts => {
+                    thread::sleep(self.backoff.delay(attempt));
+                    attempt += 1;
+                },
+                result => return result
+            }
+        }
+    }
+
+    fn authorized_client(&self) -> Result<ViperClient, ViperError> {
+        let mut client = self.builder.connect()?;
+        let auth = client.authorize(self.token.clone())?.response;
+
+        if auth.response_code != 200 {
+            return Err(ViperError::Unauthorized(
+                auth.response_code,
+                auth.response_string
+            ))
+        }
+
+        Ok(client)
+    }
+
+    fn is_disconnect(error: &ViperError) -> bool {
+        matches!(error, ViperError::ConnectError(_) | ViperError::IOError(_))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+    use crate::test_helper::{with_message_id, SimpleTcpListener, INFO_RESPONSE, OPENED};
+
+    const ACCESS_GRANTED: &str = r#"{
+        "message":"access",
+        "message-type":"response",
+        "message-id":1,
+        "response-code":200,
+        "response-string":"Access Granted"
+    }"#;
+
+
+    #[test]
+    fn test_backoff_delay() {
+        let backoff = Backoff {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(500),
+            attempts: 5
+        };
+
+        assert_eq!(backoff.delay(0), Duration::from_millis(100));
+        assert_eq!(backoff.delay(2), Duration::from_millis(400));
+        assert_eq!(backoff.delay(3), Duration::from_millis(500));
+        assert_eq!(backoff.delay(40), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_reconnects_and_retries() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3346");
+
+        let server = thread::spawn(move || {
+            // INFO is asked before authorizing, to see if UAUT is supported
+            let access = with_message_id(ACCESS_GRANTED, 2);
+
+            // The first connection drops right after authorizing
+            let first = listener.mock_replies(
+                vec![
+                    &OPENED, INFO_RESPONSE.as_bytes(), &[],
+                    &OPENED, &access, &[]
+                ]
+            ).unwrap();
+
+            // Message ids start over on a new connection, which stays
+            // up until the session hangs up.
+            let info = with_message_id(INFO_RESPONSE, 3);
+            let mut replies = vec![
+                OPENED.to_vec(), INFO_RESPONSE.as_bytes().to_vec(), vec![],
+                OPENED.to_vec(), access, vec![],
+                OPENED.to_vec(), info, vec![]
+            ].into_iter();
+            let second = listener.serve(|_| replies.next().into_iter().collect());
+
+            (first, second)
+        });
+
+        let mut session = ViperSession::new(
+            ViperClientBuilder::new("127.0.0.1", "3346"),
+            "TESTTOKEN"
+        );
+
+        let info = session.info().unwrap();
+        assert_eq!(info.model, "MSVF");
+        assert!(session.is_connected());
+        drop(session);
+
+        let (first, second) = server.join().unwrap();
+        assert_eq!(first.len(), 6);
+        assert_eq!(second.len(), 9);
+    }
+
+    #[test]
+    fn test_unauthorized() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3347");
+
+        thread::spawn(move || {
+            let denied = r#"{
+                "message":"access",
+                "message-type":"response",
+                "message-id":2,
+                "response-code":403,
+                "response-string":"Access Denied"
+            }"#;
+
+            listener.mock_replies(
+                vec![
+                    &OPENED, INFO_RESPONSE.as_bytes(), &[],
+                    &OPENED, denied.as_bytes(), &[]
+                ]
+            )
+        });
+
+        let mut session = ViperSession::new(
+            ViperClientBuilder::new("127.0.0.1", "3347"),
+            "WRONGTOKEN"
+        );
+
+        assert!(matches!(
+            session.info(),
+            Err(ViperError::Unauthorized(403, _))
+        ));
+        assert!(!session.is_connected());
+    }
+
+    #[test]
+    fn test_gives_up_after_attempts() {
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+
+        let mut session = ViperSession::new(
+            ViperClientBuilder::new("127.0.0.1", &port.to_string()),
+            "TESTTOKEN"
+        ).backoff(Backoff {
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+            attempts: 3
+        });
+
+        assert!(matches!(session.info(), Err(ViperError::ConnectError(_))));
+    }
+}
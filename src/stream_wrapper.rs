@@ -1,61 +1,133 @@
 use std::io;
 use std::io::prelude::*;
-use std::net::{TcpStream, Shutdown};
+use std::net::{TcpStream, Shutdown, ToSocketAddrs};
+use std::sync::{Mutex, MutexGuard};
 use std::time::Duration;
-use crate::command::Command;
+use crate::frame::Frame;
+use crate::multiplexer::Multiplexer;
 
 const TIMEOUT: u64 = 1000;
 
 type ByteResult = Result<Vec<u8>, io::Error>;
 
+// Channels are opened and closed through frames on these control bytes
+const CONTROL: [u8; 2] = [0x00, 0x00];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub connect: Duration,
+    pub read: Duration,
+    pub write: Duration
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            connect: Duration::from_millis(TIMEOUT),
+            read: Duration::from_millis(TIMEOUT),
+            write: Duration::from_millis(TIMEOUT)
+        }
+    }
+}
+
 pub struct StreamWrapper {
-    stream: TcpStream
+    writer: Mutex<TcpStream>,
+    control: Mutex<()>,
+    multiplexer: Multiplexer,
+    timeouts: Timeouts
 }
 
 impl StreamWrapper {
-    pub fn new(ip: String) -> StreamWrapper {
-        let stream = TcpStream::connect(ip)
-            .expect("Doorbell unavailable");
+    pub fn connect(ip: &str, timeouts: &Timeouts) -> Result<StreamWrapper, io::Error> {
+        let mut last_error = io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} did not resolve to any address", ip)
+        );
 
-        stream
-            .set_read_timeout(Some(Duration::from_millis(TIMEOUT)))
-            .unwrap();
+        for addr in ip.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeouts.connect) {
+                Ok(stream) => return Self::from_tcp(stream, timeouts),
+                Err(e) => last_error = e
+            }
+        }
 
-        stream
-            .set_write_timeout(Some(Duration::from_millis(TIMEOUT)))
-            .unwrap();
+        Err(last_error)
+    }
 
-        StreamWrapper { stream }
+    // The read timeout isn't set on the socket itself, as the reader
+    // thread would give up halfway through a frame. Instead it's applied
+    // while waiting for a frame on a channel.
+    fn from_tcp(stream: TcpStream, timeouts: &Timeouts) -> Result<StreamWrapper, io::Error> {
+        stream.set_write_timeout(Some(timeouts.write))?;
+        let reader = stream.try_clone()?;
+
+        Ok(StreamWrapper {
+            writer: Mutex::new(stream),
+            control: Mutex::new(()),
+            multiplexer: Multiplexer::spawn(reader),
+            timeouts: *timeouts
+        })
     }
 
-    pub fn execute(&mut self, b: &[u8]) -> ByteResult {
-        match self.write(b) {
-            Ok(_) => self.read(),
-            Err(e) => Err(e)
-        }
+    // Writes the bytes and waits for the reply on the same channel.
+    //
+    // The replies to opening and closing any channel all come in on the
+    // control channel, and nothing in them tells which request they
+    // answer. Those exchanges are done one at a time, so the keepalive
+    // or a call listener can't take the reply meant for another thread.
+    pub fn execute(&self, b: &[u8]) -> ByteResult {
+        let channel = [b[4], b[5]];
+        let _exchange = match channel {
+            CONTROL => {
+                let guard = self.control.lock().unwrap_or_else(|e| e.into_inner());
+
+                // A reply that only came in after its request timed out
+                // would otherwise be taken as the answer to this one.
+                self.discard(&CONTROL);
+                Some(guard)
+            },
+            _ => None
+        };
+
+        self.write(b)?;
+        self.read(&channel)
     }
 
-    pub fn die(&mut self) {
-        self.stream
-            .shutdown(Shutdown::Both)
-            .expect("shutdown call failed");
+    pub fn die(&self) {
+        let _ = self.writer().shutdown(Shutdown::Both);
     }
 
-    pub fn write(&mut self, b: &[u8]) -> Result<usize, io::Error> {
-        self.stream.write(b)
+    pub fn write(&self, b: &[u8]) -> Result<(), io::Error> {
+        self.writer().write_all(b)
     }
 
-    pub fn read(&mut self) -> ByteResult {
-        let mut head = [0; 8];
-        self.stream.read(&mut head)?;
-        let buffer_size = Command::buffer_length(
-            head[2],
-            head[3]
-        );
+    pub fn read(&self, channel: &[u8; 2]) -> ByteResult {
+        Ok(self.read_frame(channel)?.payload)
+    }
+
+    pub fn read_frame(&self, channel: &[u8; 2]) -> Result<Frame, io::Error> {
+        self.multiplexer.recv(channel, self.timeouts.read)
+    }
 
-        let mut buf = vec![0; buffer_size];
-        self.stream.read_exact(&mut buf)?;
-        Ok(buf)
+    // False once nothing can be read from the socket anymore
+    pub fn is_open(&self) -> bool {
+        !self.multiplexer.is_closed()
+    }
+
+    // Drops any frame that is still queued up for a closed channel
+    pub fn discard(&self, channel: &[u8; 2]) {
+        self.multiplexer.discard(channel)
+    }
+
+    fn writer(&self) -> MutexGuard<'_, TcpStream> {
+        self.writer.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for StreamWrapper {
+    fn drop(&mut self) {
+        self.die();
+        self.multiplexer.join();
     }
 }
 
@@ -65,18 +137,19 @@ mod tests {
     use std::str;
     use std::thread;
     use crate::test_helper::SimpleTcpListener;
-    use crate::command::CommandKind;
+    use crate::command::{Command, CommandKind};
 
     #[test]
     fn test_execute() {
         let listener = SimpleTcpListener::new("127.0.0.1:3333");
-        let mut client = StreamWrapper::new(
-            String::from("127.0.0.1:3333")
-        );
+        let client = StreamWrapper::connect(
+            "127.0.0.1:3333",
+            &Timeouts::default()
+        ).unwrap();
 
         thread::spawn(move || listener.echo());
 
-        let command = Command::make(&[65, 65], &[0, 0]);
+        let command = Command::make(&[65, 65], &[0, 0]).unwrap();
         let response = client.execute(&command).unwrap();
         assert_eq!(str::from_utf8(&response).unwrap(), "AA");
     }
@@ -84,9 +157,10 @@ mod tests {
     #[test]
     fn test_make_command() {
         let listener = SimpleTcpListener::new("127.0.0.1:3334");
-        let mut client = StreamWrapper::new(
-            String::from("127.0.0.1:3334")
-        );
+        let client = StreamWrapper::connect(
+            "127.0.0.1:3334",
+            &Timeouts::default()
+        ).unwrap();
 
         thread::spawn(move || listener.echo());
 
@@ -99,17 +173,45 @@ mod tests {
     #[test]
     fn test_make_uat_command() {
         let listener = SimpleTcpListener::new("127.0.0.1:3335");
-        let mut client = StreamWrapper::new(
-            String::from("127.0.0.1:3335")
-        );
+        let client = StreamWrapper::connect(
+            "127.0.0.1:3335",
+            &Timeouts::default()
+        ).unwrap();
 
         thread::spawn(move || listener.echo());
 
         let aut = Command::for_kind(
             CommandKind::UAUT("ABCDEFG".to_string()),
+            1,
             &[0, 0]
-        );
+        ).unwrap();
         let r = client.execute(&aut).unwrap();
         assert_eq!(r.len(), 83);
     }
+
+    #[test]
+    fn test_interleaved_channels() {
+        let listener = SimpleTcpListener::new("127.0.0.1:3337");
+        let client = StreamWrapper::connect(
+            "127.0.0.1:3337",
+            &Timeouts::default()
+        ).unwrap();
+
+        // The device answers on another channel first, before it
+        // replies to the request that was written.
+        thread::spawn(move || {
+            listener.mock_server(
+                vec![
+                    [
+                        Command::make(&[66], &[2, 2]).unwrap(),
+                        Command::make(&[65], &[1, 1]).unwrap()
+                    ].concat()
+                ]
+            )
+        });
+
+        let response = client.execute(&Command::make(&[], &[1, 1]).unwrap()).unwrap();
+        assert_eq!(response, vec![65]);
+        assert_eq!(client.read(&[2, 2]).unwrap(), vec![66]);
+    }
 }
@@ -1,8 +1,164 @@
-use crate::command::Command;
+use crate::frame::Frame;
 use std::io;
 use std::io::prelude::*;
 use std::net::TcpListener;
 
+// The device's reply to opening a channel, with 1a 12 as the control
+// bytes of its end of the channel.
+pub const OPENED: [u8; 12] = [
+    0xcd, 0xab, 0x02, 0x00, 0x04, 0x00, 0x00, 0x00,
+    0x1a, 0x12, 0x00, 0x00
+];
+
+// The same reply for a device that uses `server_channel` as its end
+pub fn open_ack(server_channel: &[u8]) -> Vec<u8> {
+    [&OPENED[0..8], server_channel, &[0x00, 0x00]].concat()
+}
+
+pub const INFO_RESPONSE: &str = r#"{
+    "message":"server-info",
+    "message-type":"response",
+    "message-id":1,
+    "response-code":200,
+    "response-string":"OK",
+    "model":"MSVF",
+    "version":"2.1.0",
+    "serial-code":"XXXXXXXX",
+    "capabilities":[
+        "user-admin-channel",
+        "user-auth-channel",
+        "configuration-channel",
+        "push-notifications-channel",
+        "cloudnext-device",
+        "fast-activation-channel",
+        "cloud-activation",
+        "face-recognition-channel"
+    ],
+    "user-auth-channel":{
+        "encryption-required":false
+    },
+    "user-admin-channel":{
+        "encryption-required":false,
+        "cloud-code-login":true
+    },
+    "configuration-channel":{
+        "internal-unit-cfg":true,
+        "direct-link-cfg":true,
+        "iu-buttons-cfg":false,
+        "api-version":2
+    },
+    "fast-activation-channel":{
+        "app":true,
+        "internal-unit":true,
+        "other-device":true
+    },
+    "cloud-activation":{
+        "cloud-activation-enable":false
+    }
+}"#;
+
+pub const CONFIGURATION_RESPONSE: &str = r#"{
+    "message":"get-configuration",
+    "message-type":"response",
+    "message-id":1,
+    "response-code":200,
+    "response-string":"OK",
+    "viper-server":{
+        "local-address":"192.168.1.10",
+        "local-tcp-port":64100,
+        "local-udp-port":64100,
+        "remote-address":"",
+        "remote-tcp-port":64100,
+        "remote-udp-port":64100
+    },
+    "viper-client":{
+        "description":"SU0EG"
+    },
+    "vip":{
+        "enabled":true,
+        "apt-address":"SB000006",
+        "apt-subaddress":2,
+        "logical-subaddress":2,
+        "apt-config":{
+            "description":"",
+            "call-divert-busy-en":false,
+            "call-divert-address":"",
+            "virtual-key-enabled":false
+        },
+        "user-parameters":{
+            "forced":true,
+            "apt-address-book":[],
+            "camera-address-book":[],
+            "rtsp-camera-address-book":[],
+            "switchboard-address-book":[],
+            "entrance-address-book":[
+                {"id":"1","name":"Front gate","apt-address":"SB100001"}
+            ],
+            "actuator-address-book":[
+                {"id":"2","name":"Stairwell light","apt-address":"SB000001","module-index":1,"output-index":2}
+            ],
+            "opendoor-address-book":[
+                {"id":"3","name":"Front gate","apt-address":"SB100001","output-index":1,"secure-mode":false},
+                {"id":"4","name":"Back door","apt-address":"SB100002","output-index":2,"secure-mode":false}
+            ],
+            "opendoor-actions":[],
+            "additional-actuator":[
+                {"id":"5","name":"Garage","apt-address":"SB000002","module-index":3,"output-index":1}
+            ]
+        }
+    }
+}"#;
+
+// Builds the reply the device confirms a CTPP request with, out of the
+// random bytes that the request was sent with.
+pub fn confirm(kind: u8, request: &[u8]) -> Vec<u8> {
+    vec![
+        kind, 0x18,
+        request[2].wrapping_add(0x80), request[3], request[5], request[4].wrapping_add(1),
+        0x00, 0x00
+    ]
+}
+
+// A ring from an entrance panel, the way the device sends it on the
+// CTPP channel of the apartment.
+pub fn ring(sequence: [u8; 4], entrance: &str, apt_address: &str) -> Vec<u8> {
+    call_packet([0x00, 0x20], sequence, entrance, apt_address)
+}
+
+// Anything the entrance panel sends during a call, which only differs
+// from a ring in its action.
+pub fn call_packet(action: [u8; 2],
+                   sequence: [u8; 4],
+                   entrance: &str,
+                   apt_address: &str) -> Vec<u8> {
+
+    let mut entrance = entrance.as_bytes().to_vec();
+    entrance.resize(9, 0xff);
+    let mut apt_address = apt_address.as_bytes().to_vec();
+    apt_address.resize(8, 0xff);
+
+    [
+        &[0xc0, 0x18][..],
+        &sequence,
+        &[0x00, 0x08],
+        &action,
+        &[0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        &[0xff, 0xff, 0xff, 0xff],
+        &entrance,
+        &[0x00],
+        &apt_address,
+        &[0x00, 0x00]
+    ].concat()
+}
+
+// Swaps out the message-id of a mocked JSON response
+pub fn with_message_id(json: &str, message_id: u32) -> Vec<u8> {
+    let mut value: serde_json::Value = serde_json::from_str(json).unwrap();
+    value["message-id"] = serde_json::json!(message_id);
+
+    serde_json::to_vec(&value).unwrap()
+}
+
 pub struct SimpleTcpListener{
     listener: TcpListener
 }
@@ -16,14 +172,8 @@ impl SimpleTcpListener {
 
     pub fn echo(&self) -> io::Result<()> {
         let (mut socket, _addr) = self.listener.accept().unwrap();
-        let mut head = [0; 8];
-        socket.read(&mut head)?;
-
-        let bl = Command::buffer_length(head[2], head[3]);
-        let mut buf = vec![0; bl];
-        socket.read(&mut buf)?;
-        socket.write(&[&head, &buf[..]].concat())?;
-        Ok(())
+        let frame = Frame::read_from(&mut socket)?;
+        frame.write_to(&mut socket)
     }
 
     pub fn mock_server(&self, responses: Vec<Vec<u8>>) -> io::Result<()> {
@@ -31,15 +181,61 @@ impl SimpleTcpListener {
         let mut count = 0;
 
         while count < responses.len() {
-            let mut head = [0; 8];
-            stream.read(&mut head)?;
-            let bl = Command::buffer_length(head[2], head[3]);
-            let mut buf = vec![0; bl];
-            stream.read(&mut buf)?;
-            stream.write(&responses[count])?;
+            Frame::read_from(&mut stream)?;
+            stream.write_all(&responses[count])?;
             count += 1;
         }
 
         Ok(())
     }
+
+    // Answers every request with the next reply, on the same
+    // channel as the request was made on. Returns the requests.
+    pub fn mock_replies(&self, replies: Vec<&[u8]>) -> io::Result<Vec<Frame>> {
+        let (mut stream, _addr) = self.listener.accept().unwrap();
+        let mut requests = vec![];
+
+        for reply in replies {
+            let request = Frame::read_from(&mut stream)?;
+            Frame::new(request.channel, reply.to_vec()).write_to(&mut stream)?;
+            requests.push(request);
+        }
+
+        Ok(requests)
+    }
+
+    // Answers every request with whatever `respond` returns for it, which
+    // can be any amount of replies. Returns the requests once the client
+    // hangs up.
+    pub fn serve<F>(&self, mut respond: F) -> Vec<Frame>
+        where F: FnMut(&Frame) -> Vec<Vec<u8>> {
+
+        self.serve_frames(|request| {
+            respond(request)
+                .into_iter()
+                .map(|reply| Frame::new(request.channel, reply))
+                .collect()
+        })
+    }
+
+    // Like `serve`, for replies that don't go to the channel the request
+    // was made on.
+    pub fn serve_frames<F>(&self, mut respond: F) -> Vec<Frame>
+        where F: FnMut(&Frame) -> Vec<Frame> {
+
+        let (mut stream, _addr) = self.listener.accept().unwrap();
+        let mut requests = vec![];
+
+        while let Ok(request) = Frame::read_from(&mut stream) {
+            for reply in respond(&request) {
+                if reply.write_to(&mut stream).is_err() {
+                    return requests
+                }
+            }
+
+            requests.push(request);
+        }
+
+        requests
+    }
 }
@@ -2,6 +2,7 @@ use dotenv::dotenv;
 use std::env;
 use std::{thread, time::Duration};
 use viper_client::{ViperClient, ViperError};
+use viper_client::command::AddressBooks;
 use viper_client::device::Device;
 
 fn main() -> Result<(), ViperError> {
@@ -34,13 +35,13 @@ fn on_connect(doorbell_ip: &String,
               doorbell_port: &String,
               token: &String) -> Result<(), ViperError> {
 
-    let mut client = ViperClient::new(doorbell_ip, doorbell_port);
+    let mut client = ViperClient::connect(doorbell_ip, doorbell_port)?;
     println!("INFO: {:?}\n", client.info()?);
     println!("UAUT: {:?}\n", client.authorize(String::from(token))?);
-    println!("UCFG: {:?}\n", client.configuration("all".to_string())?);
+    println!("UCFG: {:?}\n", client.configuration(AddressBooks::All)?);
     println!("FCRG: {:?}\n", client.face_recognition_params()?);
 
-    client.shutdown();
+    client.shutdown()?;
 
     Ok(())
 }
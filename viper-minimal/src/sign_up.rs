@@ -17,10 +17,10 @@ fn main() -> Result<(), ViperError> {
 
         if is_up {
             println!("Connected!");
-            let mut client = ViperClient::new(&doorbell_ip, &doorbell_port);
+            let mut client = ViperClient::connect(&doorbell_ip, &doorbell_port)?;
             let sign_up = client.sign_up(&email)?;
-            println!("Your token is: {}", sign_up["user-token"].to_string());
-            client.shutdown();
+            println!("Your token is: {}", sign_up.user_token);
+            client.shutdown()?;
             return Ok(())
         }
 
@@ -16,7 +16,8 @@ use dotenv::dotenv;
 use serde::Serialize;
 use serde_json::json;
 use std::{io, env};
-use viper_client::{ViperClient, ViperError};
+use viper_client::{ChannelKind, ViperClient, ViperError};
+use viper_client::command::AddressBooks;
 use viper_client::device::Device;
 
 #[derive(Debug, Display, Error)]
@@ -102,55 +103,65 @@ async fn poll_door(_req: HttpRequest,
     web::Json(Poll { available: available })
 }
 
+fn authorized_client(config: &Config) -> Result<ViperClient, ViperHTTPError> {
+    let mut client = ViperClient::connect(&config.ip, &config.port)?;
+    let auth = client.authorize(config.token.to_string())?;
+
+    if auth.response.response_code == 200 {
+        Ok(client)
+    } else {
+        println!("{:?}", auth);
+        Err(ViperHTTPError::Unauthorized)
+    }
+}
+
 #[get("/api/v1/doors")]
 async fn list_doors(
           _req: HttpRequest,
           config: web::Data<Config>
       ) -> Result<impl Responder, ViperHTTPError> {
 
-    let mut client = ViperClient::new(&config.ip, &config.port);
-    let auth_json = client.authorize(config.token.to_string())?;
+    let mut client = authorized_client(&config)?;
 
-    if auth_json["response-code"] == 200 {
-        let config = client.configuration("all".to_string())?;
-        client.shutdown();
-        Ok(web::Json(config["vip"].clone()))
-    } else {
-        println!("{:?}", auth_json);
-        Err(ViperHTTPError::Unauthorized)
-    }
+    // The vip section is passed on as the device sent it
+    let request = json!({
+        "message": "get-configuration",
+        "addressbooks": AddressBooks::All.to_string()
+    });
+    let config = client.request_json(ChannelKind::UCFG, request)?;
+    client.shutdown()?;
+
+    Ok(web::Json(config["vip"].clone()))
 }
 
+// Kept for the callers from before doors could be picked by name, this
+// opens the first door of the configuration.
 #[post("/api/v1/open")]
-async fn open_door(
+async fn open_first_door(
           _req: HttpRequest,
           config: web::Data<Config>
       ) -> Result<impl Responder, ViperHTTPError> {
 
-    let mut client = ViperClient::new(&config.ip, &config.port);
-    let auth_json = client.authorize(config.token.to_string())?;
-
-    if auth_json["response-code"] == 200 {
-        let thingy = json!({
-            "apt-address": "SB000006",
-            "apt-subaddress": 2,
-            "user-paramaters": {
-                "opendoor-address-book": [
-                    {
-                        "apt-address": "SB1000001"
-                    }
-                ]
-            }
-        });
+    let mut client = authorized_client(&config)?;
+    let configuration = client.configuration(AddressBooks::All)?;
+    client.open_door(&configuration.vip)?;
+    client.shutdown()?;
 
-        client.open_door(&thingy)?;
-        client.shutdown();
+    Ok(web::Json(DoorOpenRequest { success: true, error: vec![] }))
+}
 
-        Ok(web::Json(DoorOpenRequest { success: true, error: vec![] }))
-    } else {
-        println!("{:?}", auth_json);
-        Err(ViperHTTPError::Unauthorized)
-    }
+#[post("/api/v1/open/{name}")]
+async fn open_door(
+          _req: HttpRequest,
+          name: web::Path<String>,
+          config: web::Data<Config>
+      ) -> Result<impl Responder, ViperHTTPError> {
+
+    let mut client = authorized_client(&config)?;
+    client.open_door_by_name(&name)?;
+    client.shutdown()?;
+
+    Ok(web::Json(DoorOpenRequest { success: true, error: vec![] }))
 }
 
 #[actix_web::main]
@@ -171,6 +182,7 @@ async fn main() -> std::io::Result<()> {
             )
             .service(poll_door)
             .service(list_doors)
+            .service(open_first_door)
             .service(open_door)
             .service(Files::new("/", "./demo").index_file("index.html"))
     })